@@ -1,16 +1,104 @@
-use multiping::{Client, Message};
+use std::time::Duration;
+
+use clap::{App, Arg};
+use multiping::{Client, ClientConfig, Message};
+use tracing_subscriber::EnvFilter;
 
 #[macro_use]
-extern crate log;
+extern crate tracing;
+
+mod repl;
+
+/// How long to wait for the server to reply
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The values `--log-format` accepts
+const LOG_FORMATS: [&str; 2] = ["text", "json"];
 
 fn main() {
-    env_logger::init();
+    let matches = App::new("Multiping Client")
+        .arg(
+            Arg::with_name("address")
+                .short("a")
+                .long("address")
+                .value_name("ADDRESS")
+                .help("Connects to the server at the given address")
+                .default_value("127.0.0.1:3000")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-message-size")
+                .long("max-message-size")
+                .value_name("BYTES")
+                .help("Accepts messages up to the given size, at least the server's limit")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ping")
+                .long("ping")
+                .help("Pings the server once and exits, instead of starting a chat"),
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .long("log-format")
+                .env("MULTIPING_LOG_FORMAT")
+                .help("How to format log output")
+                .possible_values(&LOG_FORMATS)
+                .takes_value(true),
+        )
+        .get_matches();
+
+    init_logging(matches.value_of("log-format") == Some("json"));
+
+    let address = matches.value_of("address").expect("address has a default");
+    let mut config = ClientConfig::default();
+    if let Some(size) = matches.value_of("max-message-size") {
+        match size.parse() {
+            Ok(size) => config.max_message_size = size,
+            Err(_) => {
+                error!("--max-message-size must be a number");
+                return;
+            }
+        }
+    }
+    let mut client = match Client::connect_with_config(address, config) {
+        Ok(client) => client,
+        Err(e) => {
+            error!("failed to connect: {}", e);
+            return;
+        }
+    };
+
+    if let Ok(token) = std::env::var("MULTIPING_TOKEN") {
+        if let Err(e) = client.authenticate(&token, TIMEOUT) {
+            error!("failed to authenticate: {}", e);
+            return;
+        }
+    }
+
+    if !matches.is_present("ping") {
+        if let Err(e) = repl::run(&mut client) {
+            error!("{}", e);
+        }
+        return;
+    }
 
-    let client = Client::new("127.0.0.1:3000");
-    // let resp = client.send(Message::Ping).unwrap();
     debug!("ping");
-    match client.send(Message::Ping) {
+    match client.call(Message::Ping, TIMEOUT) {
         Ok(msg) => println!("respose: {}", msg),
         Err(e) => error!("error: {}", e),
     }
 }
+
+/// Log to stderr, filtered by `RUST_LOG`, as JSON objects if `json` is set or text otherwise
+fn init_logging(json: bool) {
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(std::io::stderr);
+
+    if json {
+        subscriber.json().init();
+    } else {
+        subscriber.init();
+    }
+}
@@ -0,0 +1,192 @@
+//! An interactive chat, sending the lines typed on stdin and printing what the server sends
+
+use std::io::{self, BufRead};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use multiping::{Client, DisconnectReason, Envelope, Error, Message};
+
+use crate::TIMEOUT;
+
+/// How long to wait for a message from the server before checking for input again
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The commands the user can type, shown by `/help`
+const HELP: &str = "\
+Type a line to send it to everyone, or one of:
+  /join [TOKEN]  authenticate, with MULTIPING_TOKEN by default, and catch up on history
+  /auth TOKEN    authenticate as the principal TOKEN belongs to
+  /ping          measure the round trip time to the server
+  /quit          disconnect and exit
+  /help          show this message";
+
+/// What the user asked for with a line of input
+enum Command {
+    Say(String),
+    Join(Option<String>),
+    Auth(String),
+    Ping,
+    Quit,
+    Help,
+}
+
+impl Command {
+    /// Parse a line of input
+    fn parse(line: &str) -> multiping::Result<Command> {
+        let line = line.trim();
+        if !line.starts_with('/') {
+            return Ok(Command::Say(line.to_string()));
+        }
+
+        let mut words = line.split_whitespace();
+        let name = words.next().unwrap_or_default();
+        let arg = words.next().map(str::to_string);
+
+        match (name, arg) {
+            ("/join", token) => Ok(Command::Join(token)),
+            ("/auth", Some(token)) => Ok(Command::Auth(token)),
+            ("/auth", None) => Err(Error::InvalidArguments("usage: /auth TOKEN".to_string())),
+            ("/ping", _) => Ok(Command::Ping),
+            ("/quit", _) => Ok(Command::Quit),
+            ("/help", _) => Ok(Command::Help),
+            (name, _) => Err(Error::UnknownCommand(name.to_string())),
+        }
+    }
+}
+
+/// Chat until the user quits, stdin closes or the server disconnects
+pub fn run(client: &mut Client) -> multiping::Result<()> {
+    println!("Connected, type /help for the commands");
+    let lines = spawn_stdin_reader();
+
+    loop {
+        match client.recv_timeout(POLL_INTERVAL) {
+            Ok(envelope) => {
+                if !show(&envelope) {
+                    return Ok(());
+                }
+            }
+            Err(Error::Timeout) => {}
+            Err(Error::ReceiverDisconnected) => {
+                // the stream closed without a disconnect message
+                println!("* connection lost");
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        }
+
+        // handle every line typed since the last tick, so pasted input isn't held back
+        loop {
+            let command = match lines.try_recv() {
+                Ok(line) => Command::parse(&line),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => Ok(Command::Quit),
+            };
+
+            let handled = match command {
+                Ok(Command::Quit) => {
+                    return client.send(Message::Disconnect {
+                        reason: DisconnectReason::ClientRequested,
+                    });
+                }
+                Ok(command) => handle(client, command),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = handled {
+                println!("* {}", e);
+            }
+        }
+    }
+}
+
+/// Carry out any command other than `/quit`
+fn handle(client: &mut Client, command: Command) -> multiping::Result<()> {
+    match command {
+        Command::Say(text) if text.is_empty() => {}
+        Command::Say(text) => client.send(Message::Text(text))?,
+        Command::Join(token) => {
+            let token = token
+                .or_else(|| std::env::var("MULTIPING_TOKEN").ok())
+                .ok_or_else(|| {
+                    Error::InvalidArguments(
+                        "usage: /join TOKEN, or set MULTIPING_TOKEN".to_string(),
+                    )
+                })?;
+            let principal = client.authenticate(&token, TIMEOUT)?;
+            println!("* joined as {}", principal);
+            // the replayed messages arrive with the rest
+            client.history(None, TIMEOUT)?;
+        }
+        Command::Auth(token) => {
+            let principal = client.authenticate(&token, TIMEOUT)?;
+            println!("* authenticated as {}", principal);
+        }
+        Command::Ping => {
+            let sent = Instant::now();
+            client.call(Message::Ping, TIMEOUT)?;
+            println!("* pong in {} ms", sent.elapsed().as_millis());
+        }
+        Command::Help => println!("{}", HELP),
+        Command::Quit => unreachable!("/quit is handled by the caller"),
+    }
+
+    Ok(())
+}
+
+/// Print a message from the server, returning whether the connection is still open
+fn show(envelope: &Envelope) -> bool {
+    let mut prefix = match envelope.timestamp {
+        Some(timestamp) => format!("[{}] ", format_time(timestamp)),
+        None => String::new(),
+    };
+    // set replayed history apart from what is happening now
+    if envelope.replay {
+        prefix.insert_str(0, "(history) ");
+    }
+    let sender = envelope.sender.as_deref().unwrap_or("server");
+
+    match &envelope.message {
+        Message::Text(text) => println!("{}{}: {}", prefix, sender, text),
+        Message::Direct { text, .. } => println!("{}{} (direct): {}", prefix, sender, text),
+        Message::Notice(text) => println!("{}* {}", prefix, text),
+        Message::Ping => println!("{}* {} pinged everyone", prefix, sender),
+        Message::Disconnect { reason } => {
+            println!("* disconnected: {}", reason);
+            return false;
+        }
+        msg => println!("{}* {}", prefix, msg),
+    }
+
+    true
+}
+
+/// Format a message timestamp, in milliseconds since the unix epoch, as the UTC time of day
+fn format_time(timestamp: u64) -> String {
+    let secs = timestamp / 1000 % 86400;
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Read lines from stdin on their own thread, so the chat can wait for the server and the
+/// user at once
+fn spawn_stdin_reader() -> Receiver<String> {
+    let (tx, rx) = channel();
+
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            match line {
+                Ok(line) => {
+                    if tx.send(line).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    error!("failed to read input: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+
+    rx
+}
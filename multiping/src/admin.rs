@@ -0,0 +1,108 @@
+//! Administering a running server from a connection granted admin rights with an admin token
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::auth::Principal;
+use crate::ban::{Ban, BanTarget};
+use crate::connection::{ConnectionId, ConnectionInfo};
+use crate::error::Result;
+
+/// An operation on a running server, sent in a [`crate::Message::Admin`] by a principal
+/// listed in [`crate::ServerConfig::admins`] once it has sent a [`crate::Message::AdminAuth`]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum AdminRequest {
+    /// List the open connections
+    Connections,
+    /// Disconnect a connection
+    Kick(ConnectionId),
+    /// Ban an address range or principal for `seconds`, or until it is unbanned, and
+    /// disconnect the connections it covers
+    Ban {
+        target: BanTarget,
+        #[serde(default)]
+        seconds: Option<u64>,
+    },
+    /// Lift the ban on an address range or principal
+    Unban(BanTarget),
+    /// List the bans in force
+    Bans,
+    /// Send every authenticated connection a [`crate::Message::Notice`]
+    Notice(String),
+    /// Refuse the text and direct messages a principal sends
+    Mute(Principal),
+    /// Let a muted principal send messages again
+    Unmute(Principal),
+    /// Change which log lines are recorded, with directives such as `multiping=debug`
+    SetLogFilter(String),
+}
+
+impl fmt::Display for AdminRequest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AdminRequest::Connections => write!(f, "list connections"),
+            AdminRequest::Kick(id) => write!(f, "kick connection {}", id),
+            AdminRequest::Ban {
+                target,
+                seconds: Some(seconds),
+            } => write!(f, "ban {} for {} seconds", target, seconds),
+            AdminRequest::Ban {
+                target,
+                seconds: None,
+            } => write!(f, "ban {}", target),
+            AdminRequest::Unban(target) => write!(f, "unban {}", target),
+            AdminRequest::Bans => write!(f, "list bans"),
+            AdminRequest::Notice(text) => write!(f, "notice '{}'", text),
+            AdminRequest::Mute(principal) => write!(f, "mute {}", principal),
+            AdminRequest::Unmute(principal) => write!(f, "unmute {}", principal),
+            AdminRequest::SetLogFilter(filter) => write!(f, "set log filter {}", filter),
+        }
+    }
+}
+
+/// The answer to an [`AdminRequest`], sent in a [`crate::Message::AdminReply`]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum AdminReply {
+    /// The open connections, in the order they connected
+    Connections(Vec<ConnectionInfo>),
+    /// The bans in force
+    Bans(Vec<Ban>),
+    /// The request was carried out
+    Done,
+}
+
+impl fmt::Display for AdminReply {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AdminReply::Connections(conns) => write!(f, "{} connections", conns.len()),
+            AdminReply::Bans(bans) => write!(f, "{} bans", bans.len()),
+            AdminReply::Done => write!(f, "done"),
+        }
+    }
+}
+
+/// A function changing the log filter to the given directives
+type SetLogFilter = Box<dyn FnMut(&str) -> Result<()> + Send>;
+
+/// Changes the log filter of the program embedding the server, which owns the logger
+pub(crate) struct LogFilterSetter(SetLogFilter);
+
+impl LogFilterSetter {
+    pub(crate) fn new<F>(setter: F) -> LogFilterSetter
+    where
+        F: FnMut(&str) -> Result<()> + Send + 'static,
+    {
+        LogFilterSetter(Box::new(setter))
+    }
+
+    pub(crate) fn set(&mut self, directives: &str) -> Result<()> {
+        (self.0)(directives)
+    }
+}
+
+impl fmt::Debug for LogFilterSetter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("LogFilterSetter")
+    }
+}
@@ -0,0 +1,274 @@
+//! Admission control for incoming connections
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::connection::ConnectionRegistry;
+use crate::error::{Error, Result};
+use crate::listener::Peer;
+
+/// A range of IP addresses, written as `<address>/<prefix length>`
+///
+/// A bare address is treated as a range containing only that address, and ranges are
+/// serialized in the same form.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Create a range of the addresses sharing the first `prefix` bits of `addr`
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Cidr> {
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+
+        if prefix > max {
+            return Err(Error::InvalidConfig(format!(
+                "prefix length {} is too long for {}",
+                prefix, addr
+            )));
+        }
+
+        Ok(Cidr { addr, prefix })
+    }
+
+    /// Whether `ip` is in the range, treating IPv4-mapped IPv6 addresses as IPv4
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Cidr> {
+        let invalid = || Error::InvalidConfig(format!("invalid CIDR range {}", s));
+
+        let mut parts = s.splitn(2, '/');
+        let addr: IpAddr = parts
+            .next()
+            .unwrap_or_default()
+            .parse()
+            .map_err(|_| invalid())?;
+
+        let prefix = match parts.next() {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+
+        Cidr::new(addr, prefix)
+    }
+}
+
+impl From<IpAddr> for Cidr {
+    /// The range containing only `addr`
+    fn from(addr: IpAddr) -> Cidr {
+        let prefix = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        Cidr { addr, prefix }
+    }
+}
+
+impl Serialize for Cidr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Cidr, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Limits on which connections the server accepts
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AdmissionConfig {
+    /// The maximum number of simultaneous connections, or `None` for no limit
+    pub max_connections: Option<usize>,
+    /// The maximum number of simultaneous connections from one IP address, or `None` for no limit
+    pub max_per_ip: Option<usize>,
+    /// If not empty, only addresses in these ranges are accepted
+    pub allow: Vec<Cidr>,
+    /// Addresses in these ranges are always rejected
+    pub deny: Vec<Cidr>,
+}
+
+impl AdmissionConfig {
+    /// Decide whether a new connection from `peer` may join the registry
+    ///
+    /// Only the connection limit applies to peers without an address, such as Unix socket
+    /// clients.
+    pub fn admit(&self, peer: &Peer, conns: &ConnectionRegistry) -> Result<()> {
+        if let Some(ip) = peer.ip() {
+            if self.deny.iter().any(|range| range.contains(ip))
+                || !(self.allow.is_empty() || self.allow.iter().any(|range| range.contains(ip)))
+            {
+                return Err(Error::AddressNotAllowed(ip));
+            }
+        }
+
+        if let Some(max) = self.max_connections {
+            if conns.len() >= max {
+                return Err(Error::ServerFull);
+            }
+        }
+
+        if let (Some(max), Some(ip)) = (self.max_per_ip, peer.ip()) {
+            if conns.count_from(ip) >= max {
+                return Err(Error::TooManyConnections(ip));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_ranges() {
+        assert_eq!(cidr("10.0.0.0/8"), Cidr::new(ip("10.0.0.0"), 8).unwrap());
+        assert_eq!(cidr("fd00::/8"), Cidr::new(ip("fd00::"), 8).unwrap());
+        assert_eq!(cidr("0.0.0.0/0").to_string(), "0.0.0.0/0");
+    }
+
+    #[test]
+    fn parse_bare_address_as_single_address_range() {
+        assert_eq!(cidr("192.168.1.1").to_string(), "192.168.1.1/32");
+        assert_eq!(cidr("::1").to_string(), "::1/128");
+        assert_eq!(cidr("::1"), Cidr::from(ip("::1")));
+    }
+
+    #[test]
+    fn parse_rejects_malformed_ranges() {
+        for s in &[
+            "",
+            "/8",
+            "10.0.0.0/",
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/-1",
+            "10.0.0/8",
+            "example.com/8",
+            "10.0.0.0/8/8",
+        ] {
+            assert!(s.parse::<Cidr>().is_err(), "parsed {:?}", s);
+        }
+    }
+
+    #[test]
+    fn contains_ipv4() {
+        let range = cidr("10.1.0.0/16");
+        assert!(range.contains(ip("10.1.0.0")));
+        assert!(range.contains(ip("10.1.255.255")));
+        assert!(!range.contains(ip("10.2.0.0")));
+        assert!(!range.contains(ip("9.255.255.255")));
+
+        assert!(cidr("127.0.0.1").contains(ip("127.0.0.1")));
+        assert!(!cidr("127.0.0.1").contains(ip("127.0.0.2")));
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.7")));
+    }
+
+    #[test]
+    fn contains_ignores_host_bits_of_the_range() {
+        assert!(cidr("10.1.2.3/8").contains(ip("10.200.0.1")));
+    }
+
+    #[test]
+    fn contains_ipv6() {
+        let range = cidr("2001:db8::/32");
+        assert!(range.contains(ip("2001:db8::1")));
+        assert!(range.contains(ip("2001:db8:ffff::")));
+        assert!(!range.contains(ip("2001:db9::")));
+        assert!(cidr("::/0").contains(ip("::1")));
+    }
+
+    #[test]
+    fn contains_never_matches_across_families() {
+        assert!(!cidr("0.0.0.0/0").contains(ip("::1")));
+        assert!(!cidr("::/0").contains(ip("127.0.0.1")));
+    }
+
+    #[test]
+    fn contains_treats_ipv4_mapped_addresses_as_ipv4() {
+        let range = cidr("127.0.0.0/8");
+        assert!(range.contains(ip("::ffff:127.0.0.1")));
+        assert!(!range.contains(ip("::ffff:10.0.0.1")));
+        assert!(cidr("0.0.0.0/0").contains(ip("::ffff:192.0.2.1")));
+    }
+
+    #[test]
+    fn ranges_round_trip_through_serde() {
+        let range = cidr("10.0.0.0/8");
+        let json = serde_json::to_string(&range).unwrap();
+        assert_eq!(json, "\"10.0.0.0/8\"");
+        assert_eq!(serde_json::from_str::<Cidr>(&json).unwrap(), range);
+        assert!(serde_json::from_str::<Cidr>("\"10.0.0.0/40\"").is_err());
+    }
+
+    #[test]
+    fn only_the_connection_limit_applies_to_unix_peers() {
+        let conns = ConnectionRegistry::new();
+        let mut config = AdmissionConfig {
+            max_per_ip: Some(0),
+            allow: vec![cidr("10.0.0.0/8")],
+            deny: vec![cidr("0.0.0.0/0"), cidr("::/0")],
+            ..AdmissionConfig::default()
+        };
+
+        let tcp = Peer::Tcp("127.0.0.1:4000".parse().unwrap());
+        assert!(matches!(
+            config.admit(&tcp, &conns),
+            Err(Error::AddressNotAllowed(_))
+        ));
+        assert!(config.admit(&Peer::Unix, &conns).is_ok());
+
+        config.max_connections = Some(0);
+        assert!(matches!(
+            config.admit(&Peer::Unix, &conns),
+            Err(Error::ServerFull)
+        ));
+    }
+}
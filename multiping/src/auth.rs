@@ -0,0 +1,141 @@
+//! Pre-shared token authentication
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::error::{Error, Result};
+
+/// The identity a connection has authenticated as
+pub type Principal = String;
+
+/// A set of pre-shared tokens, each of which authenticates a [`Principal`]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TokenStore {
+    /// Maps each token to the principal it authenticates
+    tokens: HashMap<String, Principal>,
+}
+
+impl TokenStore {
+    /// Create an empty token store
+    pub fn new() -> TokenStore {
+        TokenStore {
+            tokens: HashMap::new(),
+        }
+    }
+
+    /// Load a token store from a file
+    ///
+    /// See [`TokenStore::parse`] for the file format.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<TokenStore> {
+        debug!("load tokens from {}", path.as_ref().display());
+        TokenStore::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Parse a token store from a string
+    ///
+    /// Each line holds a principal followed by its token, separated by whitespace.
+    /// Blank lines and lines starting with `#` are ignored.
+    /// ```text
+    /// # principal  token
+    /// alice        0a1b2c3d
+    /// bob          4e5f6a7b
+    /// ```
+    pub fn parse(s: &str) -> Result<TokenStore> {
+        let mut store = TokenStore::new();
+
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some(principal), Some(token), None) => store.insert(principal, token),
+                _ => {
+                    return Err(Error::InvalidConfig(format!(
+                        "expected `<principal> <token>` on line {}",
+                        i + 1
+                    )))
+                }
+            }
+        }
+
+        debug!("loaded {} tokens", store.tokens.len());
+
+        Ok(store)
+    }
+
+    /// Add a token which authenticates `principal`
+    pub fn insert(&mut self, principal: &str, token: &str) {
+        self.tokens.insert(token.to_string(), principal.to_string());
+    }
+
+    /// Look up the principal authenticated by `token`
+    pub fn authenticate(&self, token: &str) -> Option<&Principal> {
+        self.tokens.get(token)
+    }
+
+    /// Whether any token authenticates `principal`
+    pub fn knows(&self, principal: &str) -> bool {
+        self.tokens.values().any(|p| p == principal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_skips_comments_and_blank_lines() {
+        let store = TokenStore::parse(
+            "# principal  token\n\
+             alice        0a1b2c3d\n\
+             \n\
+             \tbob  4e5f6a7b  \n",
+        )
+        .unwrap();
+
+        assert_eq!(store.authenticate("0a1b2c3d"), Some(&"alice".to_string()));
+        assert_eq!(store.authenticate("4e5f6a7b"), Some(&"bob".to_string()));
+        assert_eq!(store.authenticate("alice"), None);
+        assert!(store.knows("bob"));
+        assert!(!store.knows("carol"));
+    }
+
+    #[test]
+    fn parse_lets_a_principal_have_several_tokens() {
+        let store = TokenStore::parse("alice one\nalice two\n").unwrap();
+
+        assert_eq!(store.authenticate("one"), Some(&"alice".to_string()));
+        assert_eq!(store.authenticate("two"), Some(&"alice".to_string()));
+    }
+
+    #[test]
+    fn parse_rejects_malformed_lines() {
+        for s in &["alice\n", "alice 0a1b2c3d extra\n", "ok token\nbroken\n"] {
+            match TokenStore::parse(s) {
+                Err(Error::InvalidConfig(e)) => assert!(e.contains("line"), "{}", e),
+                other => panic!("parsed {:?} as {:?}", s, other),
+            }
+        }
+    }
+
+    #[test]
+    fn parse_reports_the_bad_line_number() {
+        match TokenStore::parse("# tokens\nalice one\nbroken\n") {
+            Err(Error::InvalidConfig(e)) => assert!(e.ends_with("line 3"), "{}", e),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_empty_store() {
+        assert_eq!(TokenStore::parse("").unwrap(), TokenStore::new());
+        assert_eq!(
+            TokenStore::parse("# nothing\n\n").unwrap(),
+            TokenStore::new()
+        );
+    }
+}
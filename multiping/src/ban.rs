@@ -0,0 +1,274 @@
+//! Keeping banned addresses and principals off the server, across restarts
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::admission::Cidr;
+use crate::auth::Principal;
+use crate::error::Result;
+use crate::message::timestamp;
+
+/// What a [`Ban`] keeps off the server
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum BanTarget {
+    /// Connections from a range of addresses, refused when they connect
+    Address(Cidr),
+    /// A principal, refused when it authenticates
+    Principal(Principal),
+}
+
+impl fmt::Display for BanTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BanTarget::Address(range) => write!(f, "address {}", range),
+            BanTarget::Principal(principal) => write!(f, "principal {}", principal),
+        }
+    }
+}
+
+/// A ban on an address range or principal
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Ban {
+    pub target: BanTarget,
+    /// When the ban lifts, as a [`crate::message::timestamp`], or `None` if it never does
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+}
+
+impl Ban {
+    /// Whether the ban is still in force at the time `now`
+    pub fn is_active(&self, now: u64) -> bool {
+        self.expires.is_none_or(|expires| now < expires)
+    }
+}
+
+/// The bans in force on a server, saved to a file whenever they change if the list was
+/// loaded from one
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BanList {
+    bans: Vec<Ban>,
+    path: Option<PathBuf>,
+}
+
+impl BanList {
+    /// Create an empty ban list which isn't saved
+    pub fn new() -> BanList {
+        BanList::default()
+    }
+
+    /// Load the bans saved in the JSON file at `path`, which is created when the list first
+    /// changes if it doesn't exist yet
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<BanList> {
+        let path = path.as_ref();
+        debug!("load bans from {}", path.display());
+
+        let bans = match fs::read_to_string(path) {
+            Ok(json) => serde_json::from_str(&json)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(BanList {
+            bans,
+            path: Some(path.to_path_buf()),
+        })
+    }
+
+    /// The file the list is saved to, if it is saved at all
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// The bans still in force
+    pub fn bans(&self) -> Vec<Ban> {
+        let now = timestamp();
+        self.bans
+            .iter()
+            .filter(|ban| ban.is_active(now))
+            .cloned()
+            .collect()
+    }
+
+    /// Whether connections from `ip` are banned
+    pub fn is_address_banned(&self, ip: IpAddr) -> bool {
+        self.is_banned(|target| matches!(target, BanTarget::Address(range) if range.contains(ip)))
+    }
+
+    /// Whether `principal` is banned
+    pub fn is_principal_banned(&self, principal: &str) -> bool {
+        self.is_banned(
+            |target| matches!(target, BanTarget::Principal(banned) if banned == principal),
+        )
+    }
+
+    fn is_banned<F: Fn(&BanTarget) -> bool>(&self, covers: F) -> bool {
+        let now = timestamp();
+        self.bans
+            .iter()
+            .any(|ban| ban.is_active(now) && covers(&ban.target))
+    }
+
+    /// Add a ban, replacing any earlier ban on the same target, and save the list
+    pub fn add(&mut self, ban: Ban) -> Result<()> {
+        info!("ban {}", ban.target);
+        self.bans.retain(|existing| existing.target != ban.target);
+        self.bans.push(ban);
+        self.save()
+    }
+
+    /// Lift the ban on `target` and save the list, returning whether it was banned
+    pub fn remove(&mut self, target: &BanTarget) -> Result<bool> {
+        let before = self.bans.len();
+        self.bans.retain(|ban| ban.target != *target);
+        if self.bans.len() == before {
+            return Ok(false);
+        }
+
+        info!("unban {}", target);
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Write the bans still in force to the list's file, if it has one
+    ///
+    /// The list is written to a temporary file which then replaces the old one, so a crash
+    /// part way through leaves the old list intact.
+    fn save(&mut self) -> Result<()> {
+        let now = timestamp();
+        self.bans.retain(|ban| ban.is_active(now));
+
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        debug!("save bans to {}", path.display());
+
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(&self.bans)?)?;
+        fs::rename(&tmp, path)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(range: &str) -> BanTarget {
+        BanTarget::Address(range.parse().unwrap())
+    }
+
+    fn principal(name: &str) -> BanTarget {
+        BanTarget::Principal(name.to_string())
+    }
+
+    fn ban(target: BanTarget, expires: Option<u64>) -> Ban {
+        Ban { target, expires }
+    }
+
+    #[test]
+    fn ban_expires_at_its_expiry_time() {
+        let ban = ban(principal("mallory"), Some(1000));
+        assert!(ban.is_active(999));
+        assert!(!ban.is_active(1000));
+        assert!(Ban {
+            expires: None,
+            ..ban
+        }
+        .is_active(u64::MAX));
+    }
+
+    #[test]
+    fn loading_a_missing_file_gives_an_empty_list() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bans.json");
+
+        let bans = BanList::load(&path).unwrap();
+        assert!(bans.bans().is_empty());
+        assert_eq!(bans.path(), Some(path.as_path()));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn loading_a_malformed_file_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bans.json");
+        fs::write(&path, "[{\"target\": \"everyone\"}]").unwrap();
+
+        assert!(BanList::load(&path).is_err());
+    }
+
+    #[test]
+    fn changes_are_saved_and_loaded_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bans.json");
+
+        let mut bans = BanList::load(&path).unwrap();
+        bans.add(ban(address("10.0.0.0/8"), None)).unwrap();
+        bans.add(ban(principal("mallory"), Some(u64::MAX))).unwrap();
+        bans.add(ban(principal("eve"), None)).unwrap();
+        assert!(bans.remove(&principal("eve")).unwrap());
+        assert!(!bans.remove(&principal("eve")).unwrap());
+
+        let loaded = BanList::load(&path).unwrap();
+        assert_eq!(loaded, bans);
+        assert_eq!(
+            loaded.bans(),
+            vec![
+                ban(address("10.0.0.0/8"), None),
+                ban(principal("mallory"), Some(u64::MAX)),
+            ]
+        );
+        assert!(!path.with_extension("tmp").exists());
+    }
+
+    #[test]
+    fn adding_a_ban_replaces_the_earlier_ban_on_its_target() {
+        let mut bans = BanList::new();
+        bans.add(ban(principal("mallory"), Some(u64::MAX))).unwrap();
+        bans.add(ban(principal("mallory"), None)).unwrap();
+
+        assert_eq!(bans.bans(), vec![ban(principal("mallory"), None)]);
+    }
+
+    #[test]
+    fn expired_bans_are_ignored_and_not_saved() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bans.json");
+
+        let mut bans = BanList::load(&path).unwrap();
+        bans.add(ban(principal("mallory"), Some(1))).unwrap();
+        bans.add(ban(address("192.0.2.1"), Some(1))).unwrap();
+        assert!(bans.bans().is_empty());
+        assert!(!bans.is_principal_banned("mallory"));
+        assert!(!bans.is_address_banned("192.0.2.1".parse().unwrap()));
+
+        assert_eq!(fs::read_to_string(&path).unwrap().trim(), "[]");
+    }
+
+    #[test]
+    fn address_bans_cover_their_range() {
+        let mut bans = BanList::new();
+        bans.add(ban(address("192.0.2.0/24"), None)).unwrap();
+
+        assert!(bans.is_address_banned("192.0.2.200".parse().unwrap()));
+        assert!(bans.is_address_banned("::ffff:192.0.2.200".parse().unwrap()));
+        assert!(!bans.is_address_banned("198.51.100.1".parse().unwrap()));
+        assert!(!bans.is_principal_banned("192.0.2.200"));
+    }
+
+    #[test]
+    fn a_list_without_a_file_is_not_saved() {
+        let mut bans = BanList::new();
+        bans.add(ban(principal("mallory"), None)).unwrap();
+
+        assert_eq!(bans.path(), None);
+        assert!(bans.is_principal_banned("mallory"));
+    }
+}
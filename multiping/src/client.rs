@@ -1,37 +1,514 @@
-use std::net::TcpStream;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::BufReader;
+use std::net::{Shutdown, TcpStream};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-use crate::message::Message;
-use crate::Result;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tracing::Span;
 
-/// Client
-pub struct Client<'a> {
-    server_addr: &'a str,
+use crate::auth::Principal;
+use crate::message::{Envelope, Message, MessageId, DEFAULT_MAX_MESSAGE_SIZE};
+use crate::reload::ReloadReport;
+use crate::stats::ServerStats;
+use crate::{Error, Result};
+
+/// The number of recently received message ids remembered to discard retransmissions
+const DEDUP_WINDOW: usize = 1024;
+
+/// The requests awaiting a reply, by id
+type Pending = Arc<Mutex<HashMap<MessageId, Sender<Message>>>>;
+
+/// The stream to the server, locked so the reader thread can answer pings
+type Writer = Arc<Mutex<TcpStream>>;
+
+/// A persistent connection to a server
+///
+/// Replies are matched to the requests made with [`Client::call`], and everything else
+/// the server sends (such as broadcasts from other clients) is delivered through
+/// [`Client::recv`].
+pub struct Client {
+    stream: Writer,
+    next_id: MessageId,
+    pending: Pending,
+    unsolicited: Receiver<Envelope>,
+    reader: Option<JoinHandle<Result<()>>>,
+    seen: SeenWindow,
 }
 
-impl<'a> Client<'a> {
-    /// Create a new client for the server at `server_addr`
-    pub fn new(server_addr: &'a str) -> Client {
-        Client { server_addr }
+/// The ids of the messages a client most recently received with [`Envelope::ack`], used
+/// to discard the server's retransmissions of them
+///
+/// The server retransmits unacknowledged messages when a principal authenticates again,
+/// usually on a new connection, so carry the window over to the new [`Client`] with
+/// [`Client::take_seen`] and [`Client::with_seen`].
+#[derive(Debug, Clone)]
+pub struct SeenWindow {
+    /// The ids, oldest first
+    order: VecDeque<MessageId>,
+    ids: HashSet<MessageId>,
+}
+
+impl SeenWindow {
+    pub fn new() -> SeenWindow {
+        SeenWindow {
+            order: VecDeque::with_capacity(DEDUP_WINDOW),
+            ids: HashSet::with_capacity(DEDUP_WINDOW),
+        }
     }
 
-    /// Send a message to the server and return the response
-    pub fn send(&self, msg: Message) -> Result<Message> {
-        debug!("Message::send({})", msg);
+    /// Remember the id of a received message, forgetting the oldest once the window is
+    /// full, and return whether it is new
+    pub fn insert(&mut self, id: MessageId) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
 
-        // connect to the server
-        debug!("connect to server");
-        let mut stream = TcpStream::connect(self.server_addr)?;
+        if self.order.len() == DEDUP_WINDOW {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        self.order.push_back(id);
 
-        // write the message and flush to allow the server to begin reading
-        debug!("write to stream");
-        msg.write(&mut stream)?;
+        true
+    }
+}
+
+impl Default for SeenWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-        // read the response from the server
-        let resp = Message::recv(&mut stream)?;
+/// Settings for a [`Client`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientConfig {
+    /// The size in bytes of the largest message to accept from the server
+    ///
+    /// This must be at least the server's [`crate::ServerConfig::max_message_size`], as the
+    /// server relays messages up to that size and a larger one closes the connection.
+    /// Replies which list every connection or the server's statistics can be larger still.
+    pub max_message_size: usize,
+}
 
-        Ok(resp)
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+        }
     }
 }
 
+impl Client {
+    /// Connect to the server at `server_addr` with the default [`ClientConfig`]
+    pub fn connect(server_addr: &str) -> Result<Client> {
+        Client::connect_with_config(server_addr, ClientConfig::default())
+    }
+
+    /// Connect to the server at `server_addr`
+    pub fn connect_with_config(server_addr: &str, config: ClientConfig) -> Result<Client> {
+        debug!("connect to server {}", server_addr);
+        let stream = TcpStream::connect(server_addr)?;
+        let reader_stream = stream.try_clone()?;
+        let stream = Arc::new(Mutex::new(stream));
+
+        let pending = Pending::default();
+        let (unsolicited_tx, unsolicited) = channel();
+        let span = info_span!("client", server = %server_addr);
+        let reader = spawn_reader(
+            reader_stream,
+            stream.clone(),
+            pending.clone(),
+            unsolicited_tx,
+            config.max_message_size,
+            span,
+        );
+
+        Ok(Client {
+            stream,
+            next_id: 0,
+            pending,
+            unsolicited,
+            reader: Some(reader),
+            seen: SeenWindow::new(),
+        })
+    }
+
+    /// Carry on from `seen`, the window of an earlier client, so messages it received are
+    /// skipped when the server retransmits them
+    pub fn with_seen(mut self, seen: SeenWindow) -> Client {
+        self.seen = seen;
+        self
+    }
+
+    /// Take the ids of the messages received so far, to hand to the client which replaces
+    /// this one after a reconnect
+    pub fn take_seen(&mut self) -> SeenWindow {
+        std::mem::take(&mut self.seen)
+    }
+
+    /// Authenticate with a pre-shared token, returning the principal the server recognised
+    pub fn authenticate(&mut self, token: &str, timeout: Duration) -> Result<Principal> {
+        debug!("authenticate");
+        let auth = Message::Auth {
+            token: token.to_string(),
+        };
+
+        match self.call(auth, timeout)? {
+            Message::Authenticated(principal) => {
+                debug!("authenticated as {}", principal);
+                Ok(principal)
+            }
+            msg => Err(Error::UnexpectedMessage(msg)),
+        }
+    }
+
+    /// Present an admin token for the principal the client authenticated as, to be granted
+    /// admin rights, returning the principal now acting as an admin
+    pub fn authenticate_admin(&mut self, token: &str, timeout: Duration) -> Result<Principal> {
+        debug!("authenticate as admin");
+        let auth = Message::AdminAuth {
+            token: token.to_string(),
+        };
+
+        match self.call(auth, timeout)? {
+            Message::AdminAuthenticated(principal) => {
+                debug!("authenticated as admin {}", principal);
+                Ok(principal)
+            }
+            msg => Err(Error::UnexpectedMessage(msg)),
+        }
+    }
+
+    /// Send a message to the server without waiting for a reply
+    pub fn send(&mut self, msg: Message) -> Result<()> {
+        debug!("Client::send({})", msg);
+        write(&self.stream, &Envelope::new(msg))
+    }
+
+    /// Send a message which the server will retransmit to each recipient until they
+    /// acknowledge it
+    pub fn send_reliable(&mut self, msg: Message) -> Result<()> {
+        debug!("Client::send_reliable({})", msg);
+        let envelope = Envelope {
+            ack: true,
+            ..Envelope::new(msg)
+        };
+        write(&self.stream, &envelope)
+    }
+
+    /// Send a request to the server and wait up to `timeout` for its reply
+    ///
+    /// Error replies are returned as [`Error::Remote`].
+    pub fn call(&mut self, msg: Message, timeout: Duration) -> Result<Message> {
+        debug!("Client::call({})", msg);
+
+        self.next_id += 1;
+        let id = self.next_id;
+
+        // register interest in the reply before it can possibly arrive
+        let (reply_tx, reply_rx) = channel();
+        self.pending
+            .lock()
+            .map_err(|_| Error::MutexLockError)?
+            .insert(id, reply_tx);
+
+        if let Err(e) = write(&self.stream, &Envelope::request(id, msg)) {
+            self.forget(id);
+            return Err(e);
+        }
+
+        let reply = reply_rx.recv_timeout(timeout);
+        self.forget(id);
+
+        match reply {
+            Ok(Message::Error(e)) => Err(Error::Remote(e)),
+            Ok(msg) => Ok(msg),
+            Err(RecvTimeoutError::Timeout) => Err(Error::Timeout),
+            Err(RecvTimeoutError::Disconnected) => Err(Error::ReceiverDisconnected),
+        }
+    }
+
+    /// Invoke the server command `name` and wait up to `timeout` for its result
+    pub fn command<A, R>(&mut self, name: &str, args: A, timeout: Duration) -> Result<R>
+    where
+        A: Serialize,
+        R: DeserializeOwned,
+    {
+        let msg = Message::Command {
+            name: name.to_string(),
+            args: serde_json::to_value(args)?,
+        };
+
+        match self.call(msg, timeout)? {
+            Message::CommandResult(result) => Ok(serde_json::from_value(result)?),
+            msg => Err(Error::UnexpectedMessage(msg)),
+        }
+    }
+
+    /// Ask the server to replay the messages it has retained since the timestamp `since`
+    ///
+    /// The replayed messages are delivered through [`Client::recv`], marked with
+    /// [`Envelope::replay`], before this returns the number of messages replayed.
+    pub fn history(&mut self, since: Option<u64>, timeout: Duration) -> Result<usize> {
+        match self.call(Message::History { since }, timeout)? {
+            Message::Replayed(count) => Ok(count),
+            msg => Err(Error::UnexpectedMessage(msg)),
+        }
+    }
+
+    /// Retrieve a snapshot of the server's statistics, waiting up to `timeout` for it
+    pub fn stats(&mut self, timeout: Duration) -> Result<ServerStats> {
+        match self.call(Message::Stats, timeout)? {
+            Message::StatsReport(stats) => Ok(*stats),
+            msg => Err(Error::UnexpectedMessage(msg)),
+        }
+    }
+
+    /// Ask the server to reload its config, which requires the client to be one of its
+    /// admins and to have called [`Client::authenticate_admin`], waiting up to `timeout` for the report of what changed
+    pub fn reload(&mut self, timeout: Duration) -> Result<ReloadReport> {
+        match self.call(Message::Reload, timeout)? {
+            Message::Reloaded(report) => Ok(report),
+            msg => Err(Error::UnexpectedMessage(msg)),
+        }
+    }
+
+    /// Wait for the next message from the server which isn't a reply to a request
+    ///
+    /// Messages sent with [`Envelope::ack`] are acknowledged, and retransmissions of ones
+    /// already received are skipped.
+    pub fn recv(&mut self) -> Result<Envelope> {
+        loop {
+            let envelope = self
+                .unsolicited
+                .recv()
+                .map_err(|_| Error::ReceiverDisconnected)?;
+
+            if self.accept(&envelope)? {
+                return Ok(envelope);
+            }
+        }
+    }
+
+    /// Wait up to `timeout` for the next message from the server which isn't a reply
+    ///
+    /// See [`Client::recv`].
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Envelope> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let envelope = self
+                .unsolicited
+                .recv_timeout(remaining)
+                .map_err(|e| match e {
+                    RecvTimeoutError::Timeout => Error::Timeout,
+                    RecvTimeoutError::Disconnected => Error::ReceiverDisconnected,
+                })?;
+
+            if self.accept(&envelope)? {
+                return Ok(envelope);
+            }
+        }
+    }
+
+    /// Acknowledge a message if its sender asked for it, returning whether it is new
+    fn accept(&mut self, envelope: &Envelope) -> Result<bool> {
+        let id = match envelope.id {
+            Some(id) if envelope.ack => id,
+            _ => return Ok(true),
+        };
+
+        // acknowledge duplicates too, in case the previous ack was lost
+        debug!("acknowledge message {}", id);
+        write(&self.stream, &Envelope::new(Message::Ack(id)))?;
+
+        if !self.seen.insert(id) {
+            debug!("skip duplicate message {}", id);
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    /// Stop waiting for the reply to a request
+    fn forget(&self, id: MessageId) {
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.remove(&id);
+        }
+    }
+}
+
+impl std::ops::Drop for Client {
+    fn drop(&mut self) {
+        debug!("drop client");
+
+        // closing the stream stops the reader thread
+        if let Ok(stream) = self.stream.lock() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+
+        if let Some(reader) = self.reader.take() {
+            debug!("join reader thread");
+            if reader.join().is_err() {
+                error!("error joining reader");
+            }
+        }
+    }
+}
+
+/// Write a message to the server
+fn write(stream: &Writer, envelope: &Envelope) -> Result<()> {
+    let mut stream = stream.lock().map_err(|_| Error::MutexLockError)?;
+    envelope.write(&mut *stream)
+}
+
+/// Spawn a thread which reads messages from the server, answering its pings, sending
+/// replies to the requests waiting for them and everything else to `unsolicited`
+fn spawn_reader(
+    stream: TcpStream,
+    writer: Writer,
+    pending: Pending,
+    unsolicited: Sender<Envelope>,
+    max_message_size: usize,
+    span: Span,
+) -> JoinHandle<Result<()>> {
+    thread::spawn(move || {
+        let _span = span.entered();
+
+        let mut reader = BufReader::new(stream);
+
+        loop {
+            let envelope = Envelope::recv(&mut reader, max_message_size).map_err(|e| {
+                debug!("stop reading from server: {}", e);
+                e
+            })?;
+
+            // pings from the server itself, rather than routed from other clients,
+            // measure the round trip time
+            if let (Message::Ping, Some(id), None) =
+                (&envelope.message, envelope.id, &envelope.sender)
+            {
+                debug!("answer ping {} from server", id);
+                write(&writer, &Envelope::reply(Some(id), Message::Pong))?;
+                continue;
+            }
+
+            let waiting = match envelope.reply_to {
+                Some(id) => pending
+                    .lock()
+                    .map_err(|_| Error::MutexLockError)?
+                    .remove(&id),
+                None => None,
+            };
+
+            match waiting {
+                Some(reply_tx) => {
+                    debug!("reply: {}", envelope.message);
+                    // the caller may have timed out and gone away
+                    let _ = reply_tx.send(envelope.message);
+                }
+                None => {
+                    debug!("unsolicited message: {}", envelope.message);
+                    if unsolicited.send(envelope).is_err() {
+                        debug!("client dropped, stop reading");
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    })
+}
+
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use std::io::Write;
+    use std::net::TcpListener;
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn reliable(id: MessageId) -> Envelope {
+        Envelope {
+            id: Some(id),
+            ack: true,
+            ..Envelope::new(Message::Text(format!("message {}", id)))
+        }
+    }
+
+    /// Serve each of `sessions` to a connection in turn, sending its messages and then
+    /// waiting for the client to hang up
+    fn serve(sessions: Vec<Vec<Envelope>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        thread::spawn(move || {
+            for session in sessions {
+                let (mut stream, _) = listener.accept().unwrap();
+                for envelope in session {
+                    envelope.write(&mut stream).unwrap();
+                }
+                stream.flush().unwrap();
+                let _ = std::io::copy(&mut stream, &mut std::io::sink());
+            }
+        });
+
+        addr
+    }
+
+    #[test]
+    fn window_forgets_the_oldest_ids() {
+        let mut seen = SeenWindow::new();
+        assert!(seen.insert(1));
+        assert!(!seen.insert(1));
+
+        for id in 2..=DEDUP_WINDOW as MessageId {
+            assert!(seen.insert(id));
+        }
+        assert!(!seen.insert(1));
+
+        // one more pushes the first id out of the window
+        assert!(seen.insert(DEDUP_WINDOW as MessageId + 1));
+        assert!(seen.insert(1));
+    }
+
+    #[test]
+    fn retransmissions_are_skipped() {
+        let addr = serve(vec![vec![reliable(7), reliable(7), reliable(8)]]);
+        let mut client = Client::connect(&addr).unwrap();
+
+        assert_eq!(client.recv_timeout(TIMEOUT).unwrap().id, Some(7));
+        assert_eq!(client.recv_timeout(TIMEOUT).unwrap().id, Some(8));
+    }
+
+    #[test]
+    fn retransmissions_after_a_reconnect_are_skipped() {
+        let addr = serve(vec![vec![reliable(7)], vec![reliable(7), reliable(8)]]);
+
+        let mut client = Client::connect(&addr).unwrap();
+        assert_eq!(client.recv_timeout(TIMEOUT).unwrap().id, Some(7));
+        let seen = client.take_seen();
+        drop(client);
+
+        let mut client = Client::connect(&addr).unwrap().with_seen(seen);
+        assert_eq!(client.recv_timeout(TIMEOUT).unwrap().id, Some(8));
+    }
+
+    #[test]
+    fn a_new_window_lets_retransmissions_through() {
+        let addr = serve(vec![vec![reliable(7)], vec![reliable(7)]]);
+
+        let mut client = Client::connect(&addr).unwrap();
+        assert_eq!(client.recv_timeout(TIMEOUT).unwrap().id, Some(7));
+        drop(client);
+
+        let mut client = Client::connect(&addr).unwrap();
+        assert_eq!(client.recv_timeout(TIMEOUT).unwrap().id, Some(7));
+    }
+}
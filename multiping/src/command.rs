@@ -0,0 +1,145 @@
+//! Named commands which clients can invoke on a server
+
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::auth::Principal;
+use crate::connection::ConnectionId;
+use crate::error::{Error, Result};
+
+/// A command handler with its argument and result types erased to JSON
+type Handler = Box<dyn Fn(&Caller, Value) -> Result<Value> + Send>;
+
+/// The connection which invoked a command, for handlers to authorise it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Caller {
+    /// The connection's id
+    pub id: ConnectionId,
+    /// The principal the connection authenticated as, if it has
+    pub principal: Option<Principal>,
+    /// Whether the connection has admin rights
+    pub admin: bool,
+    /// Whether the connection may see the server's statistics, as with a
+    /// [`crate::Message::Stats`]
+    pub stats_access: bool,
+}
+
+/// A registry of command handlers, looked up by name
+#[derive(Default)]
+pub struct Commands {
+    handlers: HashMap<String, Handler>,
+}
+
+impl Commands {
+    pub fn new() -> Commands {
+        Commands {
+            handlers: HashMap::new(),
+        }
+    }
+
+    /// Register `handler` as the command `name`, replacing any existing command of that name
+    ///
+    /// The handler is passed the [`Caller`] and the arguments sent by the client,
+    /// deserialized into `A`, and the value it returns is serialized as the result.
+    pub fn register<A, R, F>(&mut self, name: &str, handler: F)
+    where
+        A: DeserializeOwned,
+        R: Serialize,
+        F: Fn(&Caller, A) -> Result<R> + Send + 'static,
+    {
+        debug!("register command {}", name);
+
+        let handler = move |caller: &Caller, args: Value| {
+            let args =
+                serde_json::from_value(args).map_err(|e| Error::InvalidArguments(e.to_string()))?;
+            serde_json::to_value(handler(caller, args)?).map_err(Error::SerializeError)
+        };
+
+        self.handlers.insert(name.to_string(), Box::new(handler));
+    }
+
+    /// Run the command `name` with `args` on behalf of `caller`
+    pub fn invoke(&self, caller: &Caller, name: &str, args: Value) -> Result<Value> {
+        debug!("invoke command {} for connection {}", name, caller.id);
+
+        let handler = self
+            .handlers
+            .get(name)
+            .ok_or_else(|| Error::UnknownCommand(name.to_string()))?;
+
+        handler(caller, args)
+    }
+
+    /// The names of all registered commands
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.handlers.keys().map(String::as_str)
+    }
+}
+
+impl fmt::Debug for Commands {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.names()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::error::ErrorCode;
+
+    fn caller() -> Caller {
+        Caller {
+            id: 1,
+            principal: None,
+            admin: false,
+            stats_access: true,
+        }
+    }
+
+    #[test]
+    fn results_are_serialized() {
+        let mut commands = Commands::new();
+        commands.register("add", |_: &Caller, (a, b): (u32, u32)| Ok(a + b));
+
+        let result = commands
+            .invoke(&caller(), "add", serde_json::json!([1, 2]))
+            .unwrap();
+        assert_eq!(result, serde_json::json!(3));
+    }
+
+    #[test]
+    fn bad_arguments_and_unknown_commands_are_reported() {
+        let mut commands = Commands::new();
+        commands.register("add", |_: &Caller, (a, b): (u32, u32)| Ok(a + b));
+
+        let err = commands
+            .invoke(&caller(), "add", serde_json::json!("one"))
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidArguments);
+
+        let err = commands
+            .invoke(&caller(), "subtract", serde_json::Value::Null)
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::UnknownCommand);
+    }
+
+    #[test]
+    fn results_which_fail_to_serialize_are_internal_errors() {
+        let mut commands = Commands::new();
+        // JSON object keys must be strings
+        commands.register("pairs", |_: &Caller, ()| {
+            Ok(vec![((1, 2), 3)].into_iter().collect::<HashMap<_, _>>())
+        });
+
+        let err = commands
+            .invoke(&caller(), "pairs", serde_json::Value::Null)
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Internal);
+    }
+}
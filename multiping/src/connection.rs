@@ -1,10 +1,26 @@
-use std::collections::HashMap;
-use std::net::TcpStream;
+use std::collections::{HashMap, HashSet};
+use std::io::BufReader;
+use std::net::{IpAddr, Shutdown};
 use std::sync::mpsc::{channel, Sender, TryRecvError};
 use std::thread::{self, JoinHandle};
-
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tracing::Span;
+
+use crate::auth::Principal;
+use crate::event::{ServerEvent, Subscribers};
+use crate::listener::{Peer, Stream};
+use crate::message::{DisconnectReason, Envelope, MessageId};
+use crate::ratelimit::Buckets;
+use crate::server::Input;
+use crate::stats::SharedStats;
+use crate::timeout::Timeouts;
 use crate::{Error, Message, Result};
 
+/// How long writing to a client may block before it is disconnected as a slow consumer
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// The poll interval for a worker thread handling incoming messages
 // const POLL_READ_INTERVAL: Duration = Duration::from_millis(200);
 
@@ -12,15 +28,15 @@ use crate::{Error, Message, Result};
 // The message type for communicating with worker threads
 enum Action {
     /// Requests the worker to write the given message to its client stream
-    Forward(Message),
+    Forward(Envelope),
 
     /// Request the worker to shutdown gracefully so that it can be `join`ed for its results
     ///
     /// Note: the client must be notified of the disconnect (via a [`Message::Disconnect`])
     /// before telling the worker thread to disconnect.
     /// For instance:
-    /// ```
-    /// worker_tx.send(Action::Forward(Message::Disconnect));
+    /// ```ignore
+    /// worker_tx.send(Action::Forward(Message::Disconnect { reason }.into()));
     /// worker_tx.send(Action::Disconnect);
     /// ```
     Disconnect,
@@ -28,7 +44,26 @@ enum Action {
 
 /// The unique ID of a [`Connection`]
 pub type ConnectionId = usize;
-pub type ConnectionOutput = (ConnectionId, Message);
+
+/// A summary of a [`Connection`], for applications embedding the server
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ConnectionInfo {
+    pub id: ConnectionId,
+    /// The other end of the connection
+    pub peer: Peer,
+    /// The principal the connection has authenticated as, if any
+    pub principal: Option<Principal>,
+    /// The round trip time of the last ping the client answered, in milliseconds
+    pub rtt_ms: Option<u64>,
+    /// How long the connection has been open, in seconds
+    pub connected_secs: u64,
+    /// How long since the connection last sent or was sent a message, in seconds
+    pub idle_secs: u64,
+    /// Messages received from the client
+    pub messages_in: u64,
+    /// Messages queued to send to the client
+    pub messages_out: u64,
+}
 
 /// A connection which simultaneously sends and receives messages without blocking
 ///
@@ -49,6 +84,48 @@ pub struct Connection {
 
     /// Sends actions to the receiver worker
     recv_tx: Sender<Action>,
+
+    /// The other end of the connection
+    peer: Peer,
+
+    /// The principal this connection has authenticated as, if any
+    principal: Option<Principal>,
+
+    /// Whether the connection has presented an admin token for its principal
+    admin: bool,
+
+    /// The rate limit buckets for this connection, created on its first message
+    rate_limit: Option<Buckets>,
+
+    /// The number of malformed or unexpected messages this connection has sent
+    offences: u32,
+
+    /// The id of the ping awaiting a reply from the client, and when it was sent
+    ping: Option<(MessageId, Instant)>,
+
+    /// The round trip time of the last ping the client answered
+    rtt: Option<Duration>,
+
+    /// How long the connection may stay open
+    timeouts: Timeouts,
+
+    /// When the connection was opened
+    connected_at: Instant,
+
+    /// When the connection last sent or was sent a message, other than a ping
+    last_active: Instant,
+
+    /// Messages received from the client
+    messages_in: u64,
+
+    /// Messages queued to send to the client
+    messages_out: u64,
+
+    /// Why the client was last warned it will be disconnected, if it hasn't been active since
+    warned: Option<DisconnectReason>,
+
+    /// The span which everything done for this connection is recorded in
+    span: Span,
 }
 
 impl Connection {
@@ -56,32 +133,61 @@ impl Connection {
     ///
     /// Creates two threads:
     /// * The sender thread, which
-    pub fn new(
+    ///
+    /// Messages longer than `max_message_size` bytes are reported as [`Error::MessageTooLarge`],
+    /// the messages queued for the server and sent to the client are counted in `stats`,
+    /// and errors sent to the client are published to `events`.
+    pub(crate) fn new(
         id: ConnectionId,
-        stream: TcpStream,
-        sender: Sender<ConnectionOutput>,
-    ) -> Connection {
+        stream: Stream,
+        sender: Sender<Input>,
+        max_message_size: usize,
+        stats: SharedStats,
+        events: Subscribers,
+    ) -> Result<Connection> {
         debug!("create connection");
 
+        let peer = stream.peer()?;
+        let span = info_span!("connection", id, peer = %peer);
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+
         debug!("create worker threads");
-        let (send_worker, send_tx) = spawn_recv_worker(
+        let (recv_worker, recv_tx) = spawn_recv_worker(
             id,
             stream
                 .try_clone()
                 .expect("failed to clone connection stream"),
-            sender,
+            sender.clone(),
+            max_message_size,
+            stats.clone(),
+            span.clone(),
         );
-        let (recv_worker, recv_tx) = spawn_send_worker(stream);
+        let (send_worker, send_tx) =
+            spawn_send_worker(id, stream, sender, stats, events, span.clone());
 
         debug!("connection created successfully");
 
-        Connection {
+        Ok(Connection {
             id,
             send_worker: Some(send_worker),
             recv_worker: Some(recv_worker),
             send_tx,
             recv_tx,
-        }
+            peer,
+            principal: None,
+            admin: false,
+            rate_limit: None,
+            offences: 0,
+            ping: None,
+            rtt: None,
+            timeouts: Timeouts::default(),
+            connected_at: Instant::now(),
+            last_active: Instant::now(),
+            messages_in: 0,
+            messages_out: 0,
+            warned: None,
+            span,
+        })
     }
 
     /// Retrieve the connection's id
@@ -89,15 +195,164 @@ impl Connection {
         self.id
     }
 
-    /// Send a message to the client through the sender worker
-    pub fn forward(&mut self, msg: Message) -> Result<()> {
-        match self.send_tx.send(Action::Forward(msg)) {
-            Ok(()) => unimplemented!(),
-            Err(e) => {
-                // sender closed, treat connection as disconnected
-                error!("failed to send action to send worker: {}", e);
-                return Err(Error::SenderDisconnected);
+    /// Retrieve the other end of the connection
+    pub fn peer(&self) -> Peer {
+        self.peer
+    }
+
+    /// Summarise the connection
+    pub fn info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            id: self.id,
+            peer: self.peer,
+            principal: self.principal.clone(),
+            rtt_ms: self.rtt.map(|rtt| rtt.as_millis() as u64),
+            connected_secs: self.connected_at.elapsed().as_secs(),
+            idle_secs: self.last_active.elapsed().as_secs(),
+            messages_in: self.messages_in,
+            messages_out: self.messages_out,
+        }
+    }
+
+    /// Retrieve the span which everything done for this connection is recorded in
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    /// Retrieve the rate limit buckets for this connection
+    pub(crate) fn rate_limit(&mut self) -> &mut Option<Buckets> {
+        &mut self.rate_limit
+    }
+
+    /// Retrieve the principal this connection has authenticated as
+    pub fn principal(&self) -> Option<&Principal> {
+        self.principal.as_ref()
+    }
+
+    /// Whether the connection has authenticated and may send and receive broadcasts
+    pub fn is_authenticated(&self) -> bool {
+        self.principal.is_some()
+    }
+
+    /// Mark the connection as authenticated as `principal`, dropping any admin rights it
+    /// had as another principal
+    pub fn authenticate(&mut self, principal: Principal) {
+        debug!("connection {} authenticated as {}", self.id, principal);
+        if self.principal.as_ref() != Some(&principal) {
+            self.admin = false;
+        }
+        self.principal = Some(principal);
+    }
+
+    /// Whether the connection has presented an admin token for its principal
+    pub fn is_admin(&self) -> bool {
+        self.admin
+    }
+
+    /// Grant the connection admin rights, once it has presented an admin token
+    pub fn elevate(&mut self) {
+        debug!("connection {} elevated to admin", self.id);
+        self.admin = true;
+    }
+
+    /// Count a malformed or unexpected message from this connection, returning the total so far
+    pub fn record_offence(&mut self) -> u32 {
+        self.offences += 1;
+        self.offences
+    }
+
+    /// Ping the client with a request it should answer with a [`Message::Pong`]
+    pub fn ping(&mut self, id: MessageId) -> Result<()> {
+        self.forward(Envelope::request(id, Message::Ping))?;
+        self.ping = Some((id, Instant::now()));
+        Ok(())
+    }
+
+    /// Record the client's reply to the ping `reply_to`, returning the round trip time
+    /// if it answers the outstanding ping
+    pub fn pong(&mut self, reply_to: MessageId) -> Option<Duration> {
+        match self.ping {
+            Some((id, sent)) if id == reply_to => {
+                self.ping = None;
+                self.rtt = Some(sent.elapsed());
+                self.rtt
             }
+            _ => None,
+        }
+    }
+
+    /// Retrieve the round trip time of the last ping the client answered
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// Set how long the connection may stay open
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    /// Count a message received from the client, which puts off its idle timeout unless
+    /// it answers one of the server's pings
+    pub fn received(&mut self, message: &Message) {
+        self.messages_in += 1;
+        if !matches!(message, Message::Pong) {
+            self.touch();
+        }
+    }
+
+    /// Record that the client sent or was sent a message, putting off its idle timeout
+    pub fn touch(&mut self) {
+        self.last_active = Instant::now();
+        self.warned = None;
+    }
+
+    /// Warn the client if it is about to time out, returning why it should be disconnected
+    /// if it already has
+    pub(crate) fn expire(&mut self, now: Instant) -> Option<DisconnectReason> {
+        let (deadline, reason) = self
+            .timeouts
+            .deadline(self.connected_at, self.last_active)?;
+
+        let remaining = deadline.saturating_duration_since(now);
+        if remaining.is_zero() {
+            return Some(reason);
+        }
+
+        if remaining <= self.timeouts.warning && self.warned != Some(reason) {
+            debug!(
+                "warn connection {} of {} in {:?}",
+                self.id, reason, remaining
+            );
+            let warning = Message::DisconnectWarning {
+                reason,
+                // round up, so the client isn't told it has 0 seconds left
+                seconds: remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0),
+            };
+            if let Err(e) = self.forward(warning.into()) {
+                warn!("failed to warn connection {}: {}", self.id, e);
+            }
+            self.warned = Some(reason);
+        }
+
+        None
+    }
+
+    /// Send a message to the client through the sender worker
+    pub fn forward(&mut self, envelope: Envelope) -> Result<()> {
+        self.messages_out += 1;
+
+        // keepalives and warnings don't count as activity
+        if !matches!(
+            envelope.message,
+            Message::Ping | Message::Pong | Message::DisconnectWarning { .. }
+        ) {
+            self.touch();
+        }
+
+        if let Err(e) = self.send_tx.send(Action::Forward(envelope)) {
+            // sender closed, treat connection as disconnected
+            error!("failed to send action to send worker: {}", e);
+            return Err(Error::SenderDisconnected);
         }
 
         debug!("message forwarded");
@@ -105,13 +360,18 @@ impl Connection {
         Ok(())
     }
 
-    /// Disconnect the connection
-    pub fn disconnect(&mut self) {
-        debug!("disconnecting connection");
+    /// Disconnect the connection, telling the client why
+    pub fn disconnect(&mut self, reason: DisconnectReason) {
+        debug!("disconnecting connection: {}", reason);
+
+        if self.send_worker.is_none() && self.recv_worker.is_none() {
+            debug!("connection is already disconnected");
+            return;
+        }
 
         // tell the client we are disconnecting
         debug!("disconnecting client");
-        if let Err(e) = self.forward(Message::Disconnect) {
+        if let Err(e) = self.forward(Message::Disconnect { reason }.into()) {
             error!("failed to forward disconnect message: {}", e);
         }
 
@@ -132,7 +392,8 @@ impl Connection {
             let _ = self
                 .recv_tx
                 .send(Action::Disconnect)
-                .map_err(|e| error!("failed to send disconnect to recv worker: {:?}", e));
+                // the recv worker stops by itself after a failed read
+                .map_err(|e| debug!("failed to send disconnect to recv worker: {:?}", e));
         } else {
             warn!("recv worker is already disconnected");
         }
@@ -144,7 +405,7 @@ impl std::ops::Drop for Connection {
         debug!("dropping connection");
 
         debug!("disconnecting");
-        self.disconnect();
+        self.disconnect(DisconnectReason::ServerShutdown);
     }
 }
 
@@ -154,67 +415,70 @@ impl std::ops::Drop for Connection {
 /// * `id` - The connection's unique ID
 /// * `stream` - The stream to monitor for messages
 /// * `msg_tx` - The sender for received messages
+/// * `max_message_size` - The size in bytes of the largest message to accept
+/// * `stats` - The statistics to count queued messages in
+/// * `span` - The connection's span, which the thread runs in
 fn spawn_recv_worker(
     id: ConnectionId,
-    mut stream: TcpStream,
-    msg_tx: Sender<ConnectionOutput>,
+    stream: Stream,
+    msg_tx: Sender<Input>,
+    max_message_size: usize,
+    stats: SharedStats,
+    span: Span,
 ) -> (JoinHandle<Result<()>>, Sender<Action>) {
     debug!("spawn writing worker thread");
 
     let (action_tx, action_rx) = channel();
 
     let handle = thread::spawn(move || {
-        'main: loop {
-            // Poll for actions from sent from the main thread
-            loop {
-                debug!("checking for pending messages");
-                match action_rx.try_recv() {
-                    Ok(action) => {
-                        debug!("client thread got action: {:?}", action);
-                        debug!("forward to client");
-
-                        match action {
-                            Action::Disconnect => {
-                                debug!("disconnect recv thread");
-                                break 'main;
-                            }
-                            Action::Forward(msg) => {
-                                panic!(
-                                    "cannot forward messages from the receiver thread ({})",
-                                    msg
-                                );
-                            }
-                        }
-                    }
-                    Err(e) => match e {
-                        TryRecvError::Empty => {
-                            debug!("no pending actions");
-                            break;
-                        }
-                        TryRecvError::Disconnected => {
-                            error!("sender disconnected, cannot receive actions");
-                            return Err(Error::SenderDisconnected);
-                        }
-                    },
+        let _span = span.entered();
+
+        // keep one reader for the life of the connection so buffered messages aren't lost
+        let mut reader = BufReader::new(stream);
+
+        loop {
+            // Poll for actions sent from the main thread
+            debug!("checking for pending actions");
+            match action_rx.try_recv() {
+                Ok(Action::Disconnect) => {
+                    debug!("disconnect recv thread");
+                    break;
+                }
+                Ok(Action::Forward(envelope)) => {
+                    panic!(
+                        "cannot forward messages from the receiver thread ({})",
+                        envelope.message
+                    );
+                }
+                Err(TryRecvError::Empty) => debug!("no pending actions"),
+                Err(TryRecvError::Disconnected) => {
+                    error!("sender disconnected, cannot receive actions");
+                    return Err(Error::SenderDisconnected);
                 }
             }
 
             debug!("read message from client");
 
-            // Relay a message from the client back to the main thread
-            Message::recv(&mut stream)
-                .and_then(|msg| {
-                    info!("client sent a message: {}", msg);
-                    debug!("relaying back to main thread");
-                    msg_tx.send((id, msg)).map_err(|e| {
-                        error!("error forwarding message to server: {}", e);
-                        Error::SendError
-                    })
-                })
-                .map_err(|e| {
-                    error!("error recieving message from client: {}", e);
-                    e
-                })?;
+            // Relay a message, or the reason reading one failed, back to the main thread
+            let received = Envelope::recv(&mut reader, max_message_size);
+            // a malformed line has still been consumed, so the next message can be read
+            let failed = match &received {
+                Ok(_) | Err(Error::JsonError(_)) => false,
+                Err(_) => true,
+            };
+            match &received {
+                Ok(envelope) => info!("client sent a message: {}", envelope.message),
+                Err(e) => warn!("error recieving message from client: {}", e),
+            }
+
+            debug!("relaying back to main thread");
+            queue(&msg_tx, &stats, id, received)?;
+
+            // the stream can't be read from reliably after a failure
+            if failed {
+                debug!("stop reading from failed connection {}", id);
+                break;
+            }
         }
 
         Ok(())
@@ -223,29 +487,78 @@ fn spawn_recv_worker(
     (handle, action_tx)
 }
 
+/// Hand a message received by a connection, or the reason receiving one failed, to the
+/// server's main loop, counting it as queued until the server takes it
+fn queue(
+    sender: &Sender<Input>,
+    stats: &SharedStats,
+    id: ConnectionId,
+    received: Result<Envelope>,
+) -> Result<()> {
+    if let Ok(mut stats) = stats.lock() {
+        stats.queued += 1;
+    }
+
+    sender.send(Input::Received(id, received)).map_err(|e| {
+        error!("error forwarding message to server: {}", e);
+        // the server has stopped, so it will never take the message
+        if let Ok(mut stats) = stats.lock() {
+            stats.queued = stats.queued.saturating_sub(1);
+        }
+        Error::SendError
+    })
+}
+
 /// Spawn a worker thread which forwards outgoing messages on from the main thread
-/// to the client through the given `TcpStream`
+/// to the client through the given [`Stream`]
 ///
 /// # Arguments
 ///
+/// * `id` - The connection's unique ID
 /// * `stream` - The stream to write received messages to
+/// * `sender` - The channel to report a failed write on, so the server can close the connection
+/// * `stats` - The statistics to count sent messages in
+/// * `events` - The subscribers to tell about errors sent to the client
+/// * `span` - The connection's span, which the thread runs in
 ///
 /// Returns
-fn spawn_send_worker(mut stream: TcpStream) -> (JoinHandle<Result<()>>, Sender<Action>) {
+fn spawn_send_worker(
+    id: ConnectionId,
+    mut stream: Stream,
+    sender: Sender<Input>,
+    stats: SharedStats,
+    events: Subscribers,
+    span: Span,
+) -> (JoinHandle<Result<()>>, Sender<Action>) {
     let (action_tx, action_rx) = channel();
 
     let handle = thread::spawn(move || {
+        let _span = span.entered();
+
         // Note: it is the duty of the server to forward a disconnect `Message`
         // to the client before sending the disconnect `Action` to this thread
 
         // repeatedly block on `send_rx` until an `Action` is received
         for action in action_rx {
             match action {
-                Action::Forward(msg) => {
-                    msg.write(&mut stream).map_err(|e| {
+                Action::Forward(envelope) => {
+                    if let Err(e) = envelope.write(&mut stream) {
                         error!("failed to forward message: {}", e);
-                        e
-                    })?;
+                        // the write may have timed out, which only the server can act on
+                        let _ = queue(&sender, &stats, id, Err(e));
+                        break;
+                    }
+
+                    let bytes = envelope.encoded_len().unwrap_or(0);
+                    if let Ok(mut stats) = stats.lock() {
+                        stats.record_out(&envelope, bytes);
+                    }
+                    if let Message::Error(error) = &envelope.message {
+                        events.publish(ServerEvent::Error {
+                            id,
+                            error: error.clone(),
+                        });
+                    }
                 }
                 Action::Disconnect => {
                     debug!("disconnecting send thread");
@@ -254,6 +567,11 @@ fn spawn_send_worker(mut stream: TcpStream) -> (JoinHandle<Result<()>>, Sender<A
             }
         }
 
+        // close the stream so that the recv worker stops blocking on it
+        if let Err(e) = stream.shutdown(Shutdown::Both) {
+            debug!("failed to shutdown stream: {}", e);
+        }
+
         Ok(())
     });
 
@@ -265,6 +583,8 @@ fn spawn_send_worker(mut stream: TcpStream) -> (JoinHandle<Result<()>>, Sender<A
 pub struct ConnectionRegistry {
     next_id: ConnectionId,
     connections: HashMap<ConnectionId, Connection>,
+    stats: SharedStats,
+    events: Subscribers,
 }
 
 impl ConnectionRegistry {
@@ -272,18 +592,43 @@ impl ConnectionRegistry {
         ConnectionRegistry {
             next_id: 0,
             connections: HashMap::new(),
+            stats: SharedStats::default(),
+            events: Subscribers::default(),
         }
     }
 
-    pub fn add(&mut self, stream: TcpStream, msg_tx: Sender<ConnectionOutput>) -> ConnectionId {
+    /// Retrieve the statistics shared with the registered connections
+    pub(crate) fn stats(&self) -> &SharedStats {
+        &self.stats
+    }
+
+    /// Retrieve the subscribers to the server's events, which connections publish to
+    pub(crate) fn events(&self) -> &Subscribers {
+        &self.events
+    }
+
+    pub fn add(
+        &mut self,
+        stream: Stream,
+        msg_tx: Sender<Input>,
+        max_message_size: usize,
+    ) -> Result<ConnectionId> {
         debug!("register connection");
 
         let id = self.next_id;
         self.next_id += 1;
         debug!("id: {}", id);
 
-        let conn = Connection::new(id, stream, msg_tx);
+        let conn = Connection::new(
+            id,
+            stream,
+            msg_tx,
+            max_message_size,
+            self.stats.clone(),
+            self.events.clone(),
+        )?;
         debug!("connection object created");
+        let peer = conn.peer();
 
         // duplicate keys should be impossible as `next_id` is incremented before every insert
         if let Some(dupe) = self.connections.insert(id, conn) {
@@ -291,25 +636,38 @@ impl ConnectionRegistry {
         }
 
         debug!("connection registered successfully");
+        self.events.publish(ServerEvent::Accepted { id, peer });
 
-        id
+        Ok(id)
     }
 
-    pub fn forward_to_all(&mut self, msg: Message, source: ConnectionId) -> Result<()> {
-        debug!("forward to all connections: {}", msg);
+    /// Forward a message to every authenticated connection except the one it came from,
+    /// if it came from one
+    pub fn forward_to_all(
+        &mut self,
+        envelope: Envelope,
+        source: Option<ConnectionId>,
+    ) -> Result<()> {
+        debug!("forward to all connections: {}", envelope.message);
 
         let mut dead_conns = Vec::new();
 
         for (&id, conn) in self.connections.iter_mut() {
             // don't send to the source connection
-            if id == source {
-                debug!("skip source connection {}", source);
+            if Some(id) == source {
+                debug!("skip source connection {}", id);
+                continue;
+            }
+
+            // unauthenticated connections may not receive broadcasts
+            if !conn.is_authenticated() {
+                debug!("skip unauthenticated connection {}", id);
                 continue;
             }
 
             // try and send to the client, or mark it as dead
             debug!("forwarding to connection {}", id);
-            match conn.forward(msg.clone()) {
+            match conn.forward(envelope.clone()) {
                 Ok(_) => debug!("message forwarded"),
                 Err(e) => {
                     warn!("found dead client {}: {}", id, e);
@@ -324,13 +682,147 @@ impl ConnectionRegistry {
 
         for id in dead_conns {
             debug!("remove dead connection {}", id);
-            self.disconnect(id)
+            self.disconnect(id, DisconnectReason::ConnectionLost)
                 .unwrap_or_else(|_| panic!("failed to remove client: {}", id));
         }
 
         Ok(())
     }
 
+    /// Forward a message to every connection authenticated as `principal`, returning
+    /// how many received it
+    pub fn forward_to_principal(&mut self, envelope: Envelope, principal: &str) -> Result<usize> {
+        debug!("forward to {}: {}", principal, envelope.message);
+
+        let recipients: Vec<ConnectionId> = self
+            .connections
+            .iter()
+            .filter(|(_, conn)| conn.principal().map(String::as_str) == Some(principal))
+            .map(|(&id, _)| id)
+            .collect();
+
+        let mut delivered = 0;
+        for id in recipients {
+            match self.forward(id, envelope.clone()) {
+                Ok(()) => delivered += 1,
+                Err(e) => {
+                    warn!("found dead client {}: {}", id, e);
+                    self.disconnect(id, DisconnectReason::ConnectionLost)?;
+                }
+            }
+        }
+
+        Ok(delivered)
+    }
+
+    /// The principals of the authenticated connections other than `source`, if given
+    pub fn principals_except(&self, source: Option<ConnectionId>) -> HashSet<Principal> {
+        self.connections
+            .iter()
+            .filter(|(&id, _)| Some(id) != source)
+            .filter_map(|(_, conn)| conn.principal().cloned())
+            .collect()
+    }
+
+    /// Ping every authenticated connection with the request `id`
+    pub fn ping_all(&mut self, id: MessageId) -> Result<()> {
+        let mut dead_conns = Vec::new();
+
+        for (&conn_id, conn) in self.connections.iter_mut() {
+            if !conn.is_authenticated() {
+                continue;
+            }
+
+            if let Err(e) = conn.ping(id) {
+                warn!("found dead client {}: {}", conn_id, e);
+                dead_conns.push(conn_id);
+            }
+        }
+
+        for conn_id in dead_conns {
+            self.disconnect(conn_id, DisconnectReason::ConnectionLost)?;
+        }
+
+        Ok(())
+    }
+
+    /// Warn the connections which are about to time out, and disconnect those which have
+    pub fn expire(&mut self, now: Instant) -> Result<()> {
+        let expired: Vec<_> = self
+            .connections
+            .iter_mut()
+            .filter_map(|(&id, conn)| conn.expire(now).map(|reason| (id, reason)))
+            .collect();
+
+        for (id, reason) in expired {
+            self.disconnect(id, reason)?;
+        }
+
+        Ok(())
+    }
+
+    /// The connections' ids and the round trip times of their last answered pings
+    pub fn rtts(&self) -> impl Iterator<Item = (ConnectionId, Duration)> + '_ {
+        self.connections
+            .iter()
+            .filter_map(|(&id, conn)| conn.rtt().map(|rtt| (id, rtt)))
+    }
+
+    /// Summarise every connection, in the order they connected
+    pub fn info(&self) -> Vec<ConnectionInfo> {
+        let mut info: Vec<_> = self.connections.values().map(Connection::info).collect();
+        info.sort_by_key(|info| info.id);
+        info
+    }
+
+    /// The number of registered connections
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Whether there are no registered connections
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+
+    /// The number of registered connections from `ip`
+    pub fn count_from(&self, ip: IpAddr) -> usize {
+        self.connections
+            .values()
+            .filter(|conn| conn.peer().ip() == Some(ip))
+            .count()
+    }
+
+    /// Retrieve a connection by id
+    pub fn get(&self, id: ConnectionId) -> Result<&Connection> {
+        self.connections
+            .get(&id)
+            .ok_or(Error::InvalidConnectionId(id))
+    }
+
+    /// Retrieve a mutable reference to a connection by id
+    pub fn get_mut(&mut self, id: ConnectionId) -> Result<&mut Connection> {
+        self.connections
+            .get_mut(&id)
+            .ok_or(Error::InvalidConnectionId(id))
+    }
+
+    /// Send a message to a single connection
+    pub fn forward(&mut self, id: ConnectionId, envelope: Envelope) -> Result<()> {
+        debug!("forward to connection {}: {}", id, envelope.message);
+        self.get_mut(id)?.forward(envelope)
+    }
+
+    /// Reply to the request with id `request` from a connection
+    pub fn reply(
+        &mut self,
+        id: ConnectionId,
+        request: Option<MessageId>,
+        msg: Message,
+    ) -> Result<()> {
+        self.forward(id, Envelope::reply(request, msg))
+    }
+
     /// Remove a connection from the registry
     pub fn remove(&mut self, id: ConnectionId) -> Result<Connection> {
         debug!("remove connection {}", id);
@@ -340,20 +832,107 @@ impl ConnectionRegistry {
     }
 
     /// Remove a connection from the registry and disconnect it
-    pub fn disconnect(&mut self, id: ConnectionId) -> Result<()> {
-        debug!("disconnect connection {}", id);
-        self.remove(id).map(|mut conn| conn.disconnect())
+    pub fn disconnect(&mut self, id: ConnectionId, reason: DisconnectReason) -> Result<()> {
+        self.remove(id)?.disconnect(reason);
+        self.disconnected(id, reason);
+        Ok(())
     }
 
     /// Disconnect all connections
-    pub fn disconnect_all(&mut self) -> Result<()> {
+    pub fn disconnect_all(&mut self, reason: DisconnectReason) -> Result<()> {
         debug!("disconnect all conns");
 
-        for (id, mut conn) in self.connections.drain() {
-            debug!("disconnect connection {}", id);
-            conn.disconnect();
+        let conns: Vec<_> = self.connections.drain().collect();
+        for (id, mut conn) in conns {
+            conn.disconnect(reason);
+            self.disconnected(id, reason);
         }
 
         Ok(())
     }
+
+    /// Record why a connection was disconnected
+    fn disconnected(&self, id: ConnectionId, reason: DisconnectReason) {
+        info!("disconnected connection {}: {}", id, reason);
+        if let Ok(mut stats) = self.stats.lock() {
+            *stats.disconnects.entry(reason).or_default() += 1;
+        }
+        self.events
+            .publish(ServerEvent::Disconnected { id, reason });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::mpsc::Receiver;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    use crate::stats::ServerStats;
+
+    /// Take the next input off the queue as the server's main loop does
+    fn dequeue(input: &Receiver<Input>, stats: &SharedStats) -> Result<Envelope> {
+        let received = match input.recv_timeout(Duration::from_secs(5)).unwrap() {
+            Input::Received(_, received) => received,
+            input => panic!("unexpected input {:?}", input),
+        };
+        stats.lock().unwrap().dequeued();
+        received
+    }
+
+    #[test]
+    fn queued_messages_are_counted_until_taken() {
+        let stats: SharedStats = Arc::new(Mutex::new(ServerStats::default()));
+        let (sender, input) = channel();
+
+        queue(&sender, &stats, 1, Ok(Message::Ping.into())).unwrap();
+        queue(&sender, &stats, 1, Err(Error::Timeout)).unwrap();
+        assert_eq!(stats.lock().unwrap().queued, 2);
+
+        assert!(dequeue(&input, &stats).is_ok());
+        assert!(dequeue(&input, &stats).is_err());
+        assert_eq!(stats.lock().unwrap().queued, 0);
+        assert_eq!(stats.lock().unwrap().queue_depth.count, 2);
+
+        // nothing stays queued once the server has stopped taking messages
+        drop(input);
+        assert!(queue(&sender, &stats, 1, Ok(Message::Ping.into())).is_err());
+        assert_eq!(stats.lock().unwrap().queued, 0);
+    }
+
+    #[test]
+    fn failed_writes_are_queued_and_counted() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        // every write to the client fails from now on
+        stream.shutdown(Shutdown::Write).unwrap();
+
+        let stats: SharedStats = Arc::new(Mutex::new(ServerStats::default()));
+        let (sender, input) = channel();
+        let mut conn = Connection::new(
+            1,
+            Stream::Tcp(stream),
+            sender,
+            1024,
+            stats.clone(),
+            Subscribers::default(),
+        )
+        .unwrap();
+
+        conn.forward(Message::Ping.into()).unwrap();
+        assert!(dequeue(&input, &stats).is_err());
+
+        // the failed write closes the stream, so the recv worker fails too
+        drop(conn);
+        let failures: Vec<Input> = input.iter().collect();
+        assert_eq!(stats.lock().unwrap().queued, failures.len());
+
+        for _ in failures {
+            stats.lock().unwrap().dequeued();
+        }
+        assert_eq!(stats.lock().unwrap().queued, 0);
+    }
 }
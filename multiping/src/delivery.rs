@@ -0,0 +1,165 @@
+//! Tracking of messages which must be acknowledged by their recipients, for at-least-once delivery
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::auth::Principal;
+use crate::message::{Envelope, MessageId};
+
+/// The default number of unacknowledged messages tracked for each principal
+pub const DEFAULT_MAX_UNACKED: usize = 256;
+
+/// The messages each principal has yet to acknowledge, retransmitted when they reconnect
+#[derive(Debug)]
+pub struct Unacked {
+    capacity: usize,
+    pending: HashMap<Principal, BTreeMap<MessageId, Envelope>>,
+}
+
+impl Unacked {
+    /// Track up to `capacity` messages per principal
+    pub fn new(capacity: usize) -> Unacked {
+        Unacked {
+            capacity,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Wait for `principal` to acknowledge a message, forgetting their oldest
+    /// unacknowledged message if they already have too many
+    ///
+    /// Returns whether the message is tracked, which it isn't if it has no id or nothing
+    /// is tracked at all.
+    pub fn track(&mut self, principal: &str, envelope: Envelope) -> bool {
+        let id = match envelope.id {
+            Some(id) if self.capacity > 0 => id,
+            _ => return false,
+        };
+
+        let pending = self.pending.entry(principal.to_string()).or_default();
+        if pending.len() == self.capacity {
+            warn!(
+                "too many unacknowledged messages for {}, forgetting the oldest",
+                principal
+            );
+            let oldest = *pending.keys().next().expect("pending is full");
+            pending.remove(&oldest);
+        }
+        pending.insert(id, envelope);
+
+        true
+    }
+
+    /// Record that `principal` acknowledged the message `id`, returning whether it was pending
+    pub fn ack(&mut self, principal: &str, id: MessageId) -> bool {
+        let pending = match self.pending.get_mut(principal) {
+            Some(pending) => pending,
+            None => return false,
+        };
+
+        let acked = pending.remove(&id).is_some();
+        if pending.is_empty() {
+            self.pending.remove(principal);
+        }
+        acked
+    }
+
+    /// The messages `principal` has yet to acknowledge, oldest first
+    pub fn pending(&self, principal: &str) -> Vec<Envelope> {
+        self.pending
+            .get(principal)
+            .map(|pending| pending.values().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::message::Message;
+
+    fn text(id: u64) -> Envelope {
+        Envelope::request(id, Message::Text(format!("message {}", id)))
+    }
+
+    fn ids(envelopes: Vec<Envelope>) -> Vec<u64> {
+        envelopes
+            .into_iter()
+            .map(|envelope| envelope.id.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn tracked_messages_are_pending_until_acked() {
+        let mut unacked = Unacked::new(DEFAULT_MAX_UNACKED);
+        for id in [3, 1, 2] {
+            assert!(unacked.track("bob", text(id)));
+        }
+
+        // retransmitted oldest first
+        assert_eq!(ids(unacked.pending("bob")), vec![1, 2, 3]);
+
+        assert!(unacked.ack("bob", 2));
+        assert_eq!(ids(unacked.pending("bob")), vec![1, 3]);
+    }
+
+    #[test]
+    fn acks_only_apply_to_their_own_principal() {
+        let mut unacked = Unacked::new(DEFAULT_MAX_UNACKED);
+        unacked.track("bob", text(1));
+        unacked.track("carol", text(1));
+
+        assert!(unacked.ack("bob", 1));
+        assert!(unacked.pending("bob").is_empty());
+        assert_eq!(ids(unacked.pending("carol")), vec![1]);
+    }
+
+    #[test]
+    fn unknown_and_repeated_acks_are_ignored() {
+        let mut unacked = Unacked::new(DEFAULT_MAX_UNACKED);
+        unacked.track("bob", text(1));
+
+        assert!(!unacked.ack("bob", 2));
+        assert!(!unacked.ack("carol", 1));
+        assert!(unacked.ack("bob", 1));
+        assert!(!unacked.ack("bob", 1));
+        assert!(unacked.pending.is_empty());
+    }
+
+    #[test]
+    fn the_oldest_message_is_forgotten_at_capacity() {
+        let mut unacked = Unacked::new(2);
+        for id in 1..=3 {
+            assert!(unacked.track("bob", text(id)));
+        }
+
+        assert_eq!(ids(unacked.pending("bob")), vec![2, 3]);
+        assert!(!unacked.ack("bob", 1));
+    }
+
+    #[test]
+    fn a_capacity_of_one_keeps_the_newest_message() {
+        let mut unacked = Unacked::new(1);
+        unacked.track("bob", text(1));
+        unacked.track("bob", text(2));
+
+        assert_eq!(ids(unacked.pending("bob")), vec![2]);
+    }
+
+    #[test]
+    fn zero_capacity_tracks_nothing() {
+        let mut unacked = Unacked::new(0);
+
+        assert!(!unacked.track("bob", text(1)));
+        assert!(unacked.pending("bob").is_empty());
+        assert!(!unacked.ack("bob", 1));
+    }
+
+    #[test]
+    fn messages_without_an_id_cannot_be_tracked() {
+        let mut unacked = Unacked::new(DEFAULT_MAX_UNACKED);
+
+        assert!(!unacked.track("bob", Envelope::new(Message::Text("hi".to_string()))));
+        assert!(unacked.pending("bob").is_empty());
+    }
+}
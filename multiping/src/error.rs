@@ -1,7 +1,11 @@
 use std::fmt;
 use std::io;
+use std::net::IpAddr;
+
+use serde::{Deserialize, Serialize};
 
 use crate::connection::ConnectionId;
+use crate::message::ErrorPayload;
 use crate::Message;
 
 /// The `Result` subtype for this crate
@@ -12,6 +16,8 @@ pub type Result<T> = std::result::Result<T, Error>;
 pub enum Error {
     IoError(io::Error),
     JsonError(serde_json::Error),
+    /// A value the server produced could not be serialized
+    SerializeError(serde_json::Error),
     SenderDisconnected,
     ReceiverDisconnected,
     SendError,
@@ -19,13 +25,34 @@ pub enum Error {
     InvalidConnectionId(ConnectionId),
     MutexLockError,
     UnexpectedMessage(Message),
+    AuthenticationFailed,
+    NotAuthenticated,
+    InvalidConfig(String),
+    RateLimited,
+    ServerFull,
+    TooManyConnections(IpAddr),
+    AddressNotAllowed(IpAddr),
+    MessageTooLarge(usize),
+    /// An error reported by the other end of a connection
+    Remote(ErrorPayload),
+    Timeout,
+    UnknownCommand(String),
+    InvalidArguments(String),
+    /// Returned by command handlers which could not complete
+    CommandFailed(String),
+    UnknownRecipient(String),
+    NotAuthorised,
+    ReloadFailed(String),
+    Banned,
+    Muted,
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Error::IoError(e) => e.fmt(f),
-            Error::JsonError(e) => e.fmt(f),
+            Error::JsonError(e) => write!(f, "malformed message: {}", e),
+            Error::SerializeError(e) => write!(f, "failed to serialize: {}", e),
             Error::SenderDisconnected => write!(f, "recv failed: sender disconnected"),
             Error::SendError => write!(f, "failed to send on a channel"),
             Error::ThreadJoinError => write!(f, "failed to join a thread"),
@@ -33,10 +60,115 @@ impl fmt::Display for Error {
             Error::InvalidConnectionId(id) => write!(f, "invalid client id {}", id),
             Error::ReceiverDisconnected => write!(f, "recv failed: receiver disconnected"),
             Error::UnexpectedMessage(msg) => write!(f, "unexpected message: {}", msg),
+            Error::AuthenticationFailed => write!(f, "authentication failed"),
+            Error::NotAuthenticated => write!(f, "not authenticated"),
+            Error::InvalidConfig(e) => write!(f, "invalid config: {}", e),
+            Error::RateLimited => write!(f, "rate limit exceeded"),
+            Error::ServerFull => write!(f, "server is full"),
+            Error::TooManyConnections(ip) => write!(f, "too many connections from {}", ip),
+            Error::AddressNotAllowed(ip) => write!(f, "connections from {} are not allowed", ip),
+            Error::MessageTooLarge(max) => {
+                write!(f, "message exceeds the maximum size of {} bytes", max)
+            }
+            Error::Remote(e) => write!(f, "remote error: {}", e),
+            Error::Timeout => write!(f, "timed out"),
+            Error::UnknownCommand(name) => write!(f, "unknown command {}", name),
+            Error::InvalidArguments(e) => write!(f, "invalid arguments: {}", e),
+            Error::CommandFailed(e) => write!(f, "command failed: {}", e),
+            Error::UnknownRecipient(name) => write!(f, "unknown recipient {}", name),
+            Error::NotAuthorised => write!(f, "not authorised"),
+            Error::ReloadFailed(e) => write!(f, "reload failed: {}", e),
+            Error::Banned => write!(f, "banned from the server"),
+            Error::Muted => write!(f, "muted by an admin"),
+        }
+    }
+}
+
+impl Error {
+    /// The code sent to clients to describe this error
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::JsonError(_) => ErrorCode::InvalidMessage,
+            Error::UnexpectedMessage(_) => ErrorCode::UnexpectedMessage,
+            Error::MessageTooLarge(_) => ErrorCode::MessageTooLarge,
+            Error::AuthenticationFailed => ErrorCode::AuthenticationFailed,
+            Error::NotAuthenticated => ErrorCode::NotAuthenticated,
+            Error::RateLimited => ErrorCode::RateLimited,
+            Error::ServerFull => ErrorCode::ServerFull,
+            Error::TooManyConnections(_) => ErrorCode::TooManyConnections,
+            Error::AddressNotAllowed(_) => ErrorCode::AddressNotAllowed,
+            Error::Remote(e) => e.code,
+            Error::UnknownCommand(_) => ErrorCode::UnknownCommand,
+            Error::InvalidArguments(_) => ErrorCode::InvalidArguments,
+            Error::CommandFailed(_) => ErrorCode::CommandFailed,
+            Error::UnknownRecipient(_) => ErrorCode::UnknownRecipient,
+            Error::NotAuthorised => ErrorCode::NotAuthorised,
+            Error::ReloadFailed(_) => ErrorCode::ReloadFailed,
+            Error::Banned => ErrorCode::Banned,
+            Error::Muted => ErrorCode::Muted,
+            Error::InvalidConnectionId(_) => ErrorCode::UnknownConnection,
+            Error::IoError(_)
+            | Error::SenderDisconnected
+            | Error::ReceiverDisconnected
+            | Error::SendError
+            | Error::ThreadJoinError
+            | Error::MutexLockError
+            | Error::InvalidConfig(_)
+            | Error::SerializeError(_)
+            | Error::Timeout => ErrorCode::Internal,
         }
     }
 }
 
+/// The machine readable kind of an error reported to a client in a [`Message::Error`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+pub enum ErrorCode {
+    /// The message could not be parsed
+    InvalidMessage,
+    /// The message was valid but not expected at this point
+    UnexpectedMessage,
+    /// The message was larger than the server accepts
+    MessageTooLarge,
+    /// The token sent to authenticate was not recognised
+    AuthenticationFailed,
+    /// The client must authenticate before sending anything else
+    NotAuthenticated,
+    /// The client is sending too quickly
+    RateLimited,
+    /// The server has reached its connection limit
+    ServerFull,
+    /// The client's address has reached its connection limit
+    TooManyConnections,
+    /// The client's address may not connect
+    AddressNotAllowed,
+    /// No command is registered with the requested name
+    UnknownCommand,
+    /// The arguments to a command were not what it expected
+    InvalidArguments,
+    /// The command ran but could not complete
+    CommandFailed,
+    /// No principal exists with the name a direct message was sent to
+    UnknownRecipient,
+    /// The client's principal may not make this request
+    NotAuthorised,
+    /// The server could not reload its config, and kept the old one
+    ReloadFailed,
+    /// The client, or its address, is banned
+    Banned,
+    /// The client's principal was muted by an admin and may not send messages
+    Muted,
+    /// No connection exists with the id an admin asked for
+    UnknownConnection,
+    /// Something went wrong on the server
+    Internal,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Error {
         Error::IoError(err)
@@ -0,0 +1,136 @@
+//! A stream of what happens inside a server, for applications embedding it
+
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+use crate::auth::Principal;
+use crate::connection::ConnectionId;
+use crate::listener::Peer;
+use crate::message::{DisconnectReason, Envelope, ErrorPayload};
+
+/// Something which happened inside a server, sent to every subscriber
+#[derive(Debug, Clone)]
+pub enum ServerEvent {
+    /// A listener accepted a connection
+    Accepted { id: ConnectionId, peer: Peer },
+    /// A connection authenticated, or was admitted without authenticating
+    Authenticated {
+        id: ConnectionId,
+        principal: Principal,
+    },
+    /// A connection sent a message, which is yet to be checked against the limits
+    ///
+    /// Tokens in the message are redacted.
+    Received {
+        id: ConnectionId,
+        envelope: Envelope,
+    },
+    /// A message was stamped and sent on to its recipients, as a broadcast or a direct
+    /// message, from the connection `source` or the server itself if `None`
+    Routed {
+        source: Option<ConnectionId>,
+        envelope: Envelope,
+    },
+    /// A connection was closed and removed from the server
+    Disconnected {
+        id: ConnectionId,
+        reason: DisconnectReason,
+    },
+    /// An error was sent to a connection, or the server failed to handle its message
+    Error {
+        id: ConnectionId,
+        error: ErrorPayload,
+    },
+}
+
+/// The channels events are published to, shared by every part of a server
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Subscribers(Arc<Mutex<Vec<Sender<ServerEvent>>>>);
+
+impl Subscribers {
+    /// Receive every event published from now on
+    ///
+    /// The channel is unbounded, so a subscriber which stops reading should drop its
+    /// receiver, after which it is forgotten.
+    pub(crate) fn subscribe(&self) -> Receiver<ServerEvent> {
+        let (tx, rx) = channel();
+        self.0.lock().expect("mutex poisoned").push(tx);
+        rx
+    }
+
+    /// Send an event to every subscriber, forgetting those which have gone away
+    pub(crate) fn publish(&self, event: ServerEvent) {
+        let mut subscribers = self.0.lock().expect("mutex poisoned");
+        if subscribers.is_empty() {
+            return;
+        }
+
+        subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn disconnected(id: ConnectionId) -> ServerEvent {
+        ServerEvent::Disconnected {
+            id,
+            reason: DisconnectReason::ClientRequested,
+        }
+    }
+
+    fn id(event: ServerEvent) -> ConnectionId {
+        match event {
+            ServerEvent::Disconnected { id, .. } => id,
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn every_subscriber_receives_every_event_in_order() {
+        let subscribers = Subscribers::default();
+        let first = subscribers.subscribe();
+        let second = subscribers.subscribe();
+
+        subscribers.publish(disconnected(1));
+        subscribers.publish(disconnected(2));
+
+        for rx in &[first, second] {
+            assert_eq!(rx.try_iter().map(id).collect::<Vec<_>>(), vec![1, 2]);
+        }
+    }
+
+    #[test]
+    fn subscribers_only_receive_events_published_after_they_subscribe() {
+        let subscribers = Subscribers::default();
+        subscribers.publish(disconnected(1));
+
+        let rx = subscribers.subscribe();
+        subscribers.publish(disconnected(2));
+
+        assert_eq!(rx.try_iter().map(id).collect::<Vec<_>>(), vec![2]);
+    }
+
+    #[test]
+    fn dropped_subscribers_are_forgotten() {
+        let subscribers = Subscribers::default();
+        let kept = subscribers.subscribe();
+        drop(subscribers.subscribe());
+
+        subscribers.publish(disconnected(1));
+
+        assert_eq!(subscribers.0.lock().unwrap().len(), 1);
+        assert_eq!(id(kept.try_recv().unwrap()), 1);
+    }
+
+    #[test]
+    fn clones_share_their_subscribers() {
+        let subscribers = Subscribers::default();
+        let rx = subscribers.subscribe();
+
+        subscribers.clone().publish(disconnected(1));
+
+        assert_eq!(id(rx.try_recv().unwrap()), 1);
+    }
+}
@@ -0,0 +1,109 @@
+//! Controlling a server which runs on its own thread
+
+use std::net::SocketAddr;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use crate::connection::{ConnectionId, ConnectionInfo, ConnectionRegistry};
+use crate::error::{Error, Result};
+use crate::event::{ServerEvent, Subscribers};
+use crate::listener::ListenAddr;
+use crate::message::{Envelope, Message};
+use crate::reload::ReloadHandle;
+use crate::server::Input;
+use crate::stats::{ServerStats, StatsHandle};
+
+/// Controls a server started with [`crate::Server::start`]
+///
+/// Dropping the handle shuts the server down.
+#[derive(Debug)]
+pub struct ServerHandle {
+    pub(crate) local_addrs: Vec<ListenAddr>,
+    pub(crate) input: Sender<Input>,
+    pub(crate) connections: Arc<Mutex<ConnectionRegistry>>,
+    pub(crate) stats: StatsHandle,
+    pub(crate) events: Subscribers,
+    pub(crate) thread: Option<JoinHandle<()>>,
+}
+
+impl ServerHandle {
+    /// The addresses the server's listeners are bound to, with the ports chosen for any
+    /// bound to port 0
+    pub fn local_addrs(&self) -> &[ListenAddr] {
+        &self.local_addrs
+    }
+
+    /// The address of the first TCP listener, which clients can connect to
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addrs.iter().find_map(|addr| match addr {
+            ListenAddr::Tcp(addr) => addr.parse().ok(),
+            ListenAddr::Unix(_) => None,
+        })
+    }
+
+    /// Send a message from the server to every authenticated connection, stamped and
+    /// retained in the history like a message from a client
+    pub fn inject(&self, message: Message) -> Result<()> {
+        self.input
+            .send(Input::Inject(message))
+            .map_err(|_| Error::SendError)
+    }
+
+    /// Send a message from the server to a single connection
+    pub fn send_to(&self, id: ConnectionId, message: Message) -> Result<()> {
+        self.connections
+            .lock()
+            .map_err(|_| Error::MutexLockError)?
+            .forward(id, Envelope::new(message))
+    }
+
+    /// Summarise the open connections
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        self.connections.lock().expect("mutex poisoned").info()
+    }
+
+    /// Receive every [`ServerEvent`] from now on
+    ///
+    /// See [`crate::Server::subscribe`].
+    pub fn subscribe(&self) -> Receiver<ServerEvent> {
+        self.events.subscribe()
+    }
+
+    /// Take a snapshot of the server's statistics
+    pub fn stats(&self) -> ServerStats {
+        self.stats.stats()
+    }
+
+    /// Retrieve a handle for asking the server to reload its config
+    pub fn reload_handle(&self) -> ReloadHandle {
+        ReloadHandle {
+            input: self.input.clone(),
+        }
+    }
+
+    /// Stop the server, disconnecting every client, and wait for it to finish
+    pub fn shutdown(mut self) -> Result<()> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        let thread = match self.thread.take() {
+            Some(thread) => thread,
+            None => return Ok(()),
+        };
+
+        debug!("shut down server");
+        // the server may have stopped already, in which case there's nothing to tell
+        let _ = self.input.send(Input::Shutdown);
+        thread.join().map_err(|_| Error::ThreadJoinError)
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop() {
+            error!("failed to shut down server: {}", e);
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! A bounded buffer of recently routed messages, replayed to clients which join late
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::message::{self, Envelope, Message};
+
+/// How much history the server retains
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HistoryConfig {
+    /// The number of messages to keep, or 0 to keep none
+    pub capacity: usize,
+    /// How long to keep each message, or `None` to keep it until it is pushed out by newer ones
+    pub max_age: Option<Duration>,
+}
+
+/// A ring buffer of the most recently routed messages
+#[derive(Debug)]
+pub struct History {
+    config: HistoryConfig,
+    entries: VecDeque<(Instant, Envelope)>,
+}
+
+impl History {
+    pub fn new(config: HistoryConfig) -> History {
+        History {
+            entries: VecDeque::with_capacity(config.capacity),
+            config,
+        }
+    }
+
+    /// Retain a routed message, evicting the oldest if the buffer is full
+    ///
+    /// Only broadcast text is retained, as pings are only meaningful live and direct
+    /// messages are only for their recipient. The message's age is measured from its
+    /// timestamp, so one replayed from the journal expires as if it had been kept all along.
+    pub fn record(&mut self, envelope: Envelope) {
+        if self.config.capacity == 0 {
+            return;
+        }
+
+        if let Message::Ping | Message::Direct { .. } = envelope.message {
+            return;
+        }
+
+        let age = envelope
+            .timestamp
+            .map(|timestamp| Duration::from_millis(message::timestamp().saturating_sub(timestamp)))
+            .unwrap_or_default();
+        if self.config.max_age.is_some_and(|max_age| age > max_age) {
+            return;
+        }
+        let recorded = Instant::now().checked_sub(age).unwrap_or_else(Instant::now);
+
+        if self.entries.len() == self.config.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((recorded, envelope));
+    }
+
+    /// The retained messages routed at or after the timestamp `since`, oldest first
+    pub fn since(&mut self, since: Option<u64>) -> Vec<Envelope> {
+        self.expire();
+
+        self.entries
+            .iter()
+            .map(|(_, envelope)| envelope)
+            .filter(|envelope| match (since, envelope.timestamp) {
+                (Some(since), Some(timestamp)) => timestamp >= since,
+                _ => true,
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Forget messages older than the configured maximum age
+    fn expire(&mut self) {
+        if let Some(max_age) = self.config.max_age {
+            while let Some((recorded, _)) = self.entries.front() {
+                if recorded.elapsed() <= max_age {
+                    break;
+                }
+                self.entries.pop_front();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(id: u64, timestamp: u64) -> Envelope {
+        Envelope {
+            timestamp: Some(timestamp),
+            ..Envelope::request(id, Message::Text(format!("message {}", id)))
+        }
+    }
+
+    fn ids(envelopes: Vec<Envelope>) -> Vec<u64> {
+        envelopes
+            .into_iter()
+            .map(|envelope| envelope.id.unwrap())
+            .collect()
+    }
+
+    fn history(capacity: usize, max_age: Option<Duration>) -> History {
+        History::new(HistoryConfig { capacity, max_age })
+    }
+
+    #[test]
+    fn oldest_messages_are_evicted_at_capacity() {
+        let mut history = history(3, None);
+        let now = message::timestamp();
+        for id in 1..=5 {
+            history.record(text(id, now));
+        }
+
+        assert_eq!(ids(history.since(None)), vec![3, 4, 5]);
+    }
+
+    #[test]
+    fn zero_capacity_keeps_nothing() {
+        let mut history = history(0, None);
+        history.record(text(1, message::timestamp()));
+
+        assert!(history.since(None).is_empty());
+    }
+
+    #[test]
+    fn messages_expire_after_the_maximum_age() {
+        let mut history = history(10, Some(Duration::from_secs(60)));
+        let now = message::timestamp();
+        history.record(text(1, now));
+        history.record(text(2, now));
+
+        // as if the first had been recorded a minute and a half ago
+        history.entries[0].0 = Instant::now() - Duration::from_secs(90);
+
+        assert_eq!(ids(history.since(None)), vec![2]);
+        assert_eq!(history.entries.len(), 1);
+    }
+
+    #[test]
+    fn messages_without_a_maximum_age_never_expire() {
+        let mut history = history(10, None);
+        history.record(text(1, 0));
+
+        assert_eq!(ids(history.since(None)), vec![1]);
+    }
+
+    #[test]
+    fn pings_and_direct_messages_are_not_retained() {
+        let mut history = history(10, None);
+        history.record(Envelope::request(1, Message::Ping));
+        history.record(Envelope::request(
+            2,
+            Message::Direct {
+                to: "bob".to_string(),
+                text: "psst".to_string(),
+            },
+        ));
+        history.record(text(3, message::timestamp()));
+
+        assert_eq!(ids(history.since(None)), vec![3]);
+    }
+
+    #[test]
+    fn since_filters_by_timestamp() {
+        let mut history = history(10, None);
+        history.record(text(1, 1000));
+        history.record(text(2, 2000));
+        history.record(text(3, 3000));
+
+        assert_eq!(ids(history.since(Some(2000))), vec![2, 3]);
+        assert_eq!(ids(history.since(Some(4000))), Vec::<u64>::new());
+        assert_eq!(ids(history.since(None)), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn replayed_messages_keep_their_age() {
+        let mut history = history(10, Some(Duration::from_secs(60)));
+        let now = message::timestamp();
+
+        history.record(text(1, now - 120_000));
+        history.record(text(2, now - 30_000));
+        history.record(text(3, now));
+
+        assert_eq!(ids(history.since(None)), vec![2, 3]);
+        // the retained message is expired once its own age passes the limit
+        let (recorded, _) = history.entries.front().unwrap();
+        assert!(recorded.elapsed() >= Duration::from_secs(30));
+    }
+}
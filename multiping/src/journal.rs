@@ -0,0 +1,554 @@
+//! A durable, append-only log of every message the server routes
+//!
+//! The journal is a directory of numbered segment files, each holding one JSON encoded
+//! [`Envelope`] per line. New messages are appended to the newest segment, and a new
+//! segment is started once it grows past [`JournalConfig::segment_size`]. Whole segments
+//! are deleted, oldest first, to keep the journal within its retention limits, whenever a
+//! segment is started and at least every [`TRIM_INTERVAL`] while messages are appended.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Split};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::error::{Error, Result};
+use crate::message::Envelope;
+
+/// The file extension of journal segments
+const SEGMENT_EXTENSION: &str = "journal";
+
+/// How often segments past their maximum age are deleted while the journal is appended to
+const TRIM_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The default size in bytes at which a new segment is started
+pub const DEFAULT_SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
+
+/// When to flush appended messages to disk with `fsync`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncPolicy {
+    /// After every message
+    Always,
+    /// After every `n` messages
+    Every(u32),
+    /// Only when a segment is finished, leaving the rest to the operating system
+    Never,
+}
+
+impl FromStr for SyncPolicy {
+    type Err = Error;
+
+    /// Parse `always`, `never` or `every:<n>`
+    fn from_str(s: &str) -> Result<SyncPolicy> {
+        match s {
+            "always" => Ok(SyncPolicy::Always),
+            "never" => Ok(SyncPolicy::Never),
+            _ => s
+                .strip_prefix("every:")
+                .and_then(|n| n.parse().ok())
+                .filter(|&n| n > 0)
+                .map(SyncPolicy::Every)
+                .ok_or_else(|| {
+                    Error::InvalidConfig(format!(
+                        "unknown sync policy {}, expected always, never or every:<n>",
+                        s
+                    ))
+                }),
+        }
+    }
+}
+
+/// Where and how the journal is kept
+#[derive(Debug, Clone, PartialEq)]
+pub struct JournalConfig {
+    /// The directory holding the segment files
+    pub dir: PathBuf,
+    /// When appended messages are flushed to disk
+    pub sync: SyncPolicy,
+    /// The size in bytes at which a new segment is started
+    pub segment_size: u64,
+    /// The total size in bytes to keep, or `None` for no limit
+    pub max_size: Option<u64>,
+    /// How long to keep finished segments, or `None` to keep them forever
+    pub max_age: Option<Duration>,
+}
+
+impl JournalConfig {
+    /// A journal in `dir` which syncs every message and is never trimmed
+    pub fn new<P: Into<PathBuf>>(dir: P) -> JournalConfig {
+        JournalConfig {
+            dir: dir.into(),
+            sync: SyncPolicy::Always,
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            max_size: None,
+            max_age: None,
+        }
+    }
+}
+
+/// An open journal which messages can be appended to
+#[derive(Debug)]
+pub struct Journal {
+    config: JournalConfig,
+    /// The index of the segment being appended to
+    index: u64,
+    segment: File,
+    segment_len: u64,
+    /// The number of messages appended since the last sync
+    unsynced: u32,
+    /// When the journal was last trimmed to its retention limits
+    trimmed_at: Instant,
+}
+
+impl Journal {
+    /// Open the journal described by `config`, creating its directory if necessary
+    ///
+    /// Messages are appended to the newest existing segment, after cutting off any message
+    /// left incomplete at its end by a crash.
+    pub fn open(config: JournalConfig) -> Result<Journal> {
+        debug!("open journal in {}", config.dir.display());
+        fs::create_dir_all(&config.dir)?;
+
+        let index = segments(&config.dir)?
+            .last()
+            .map(|(index, _)| *index)
+            .unwrap_or(0);
+        truncate_torn_tail(&segment_path(&config.dir, index))?;
+        let segment = open_segment(&config.dir, index)?;
+        let segment_len = segment.metadata()?.len();
+
+        let mut journal = Journal {
+            config,
+            index,
+            segment,
+            segment_len,
+            unsynced: 0,
+            trimmed_at: Instant::now(),
+        };
+        journal.trim()?;
+
+        Ok(journal)
+    }
+
+    /// Append a message to the journal
+    pub fn append(&mut self, envelope: &Envelope) -> Result<()> {
+        if self.segment_len >= self.config.segment_size {
+            self.rotate()?;
+        }
+
+        envelope.write(&mut self.segment)?;
+        self.segment_len += envelope.encoded_len()? as u64;
+        self.unsynced += 1;
+
+        match self.config.sync {
+            SyncPolicy::Always => self.sync()?,
+            SyncPolicy::Every(n) if self.unsynced >= n => self.sync()?,
+            _ => {}
+        }
+
+        // segments age even when the current one is far from full
+        if self.config.max_age.is_some() && self.trimmed_at.elapsed() >= TRIM_INTERVAL {
+            self.trim()?;
+        }
+
+        Ok(())
+    }
+
+    /// Flush appended messages to disk
+    pub fn sync(&mut self) -> Result<()> {
+        self.segment.sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    /// Finish the current segment and start appending to a new one
+    fn rotate(&mut self) -> Result<()> {
+        self.sync()?;
+
+        self.index += 1;
+        debug!("start journal segment {}", self.index);
+        self.segment = open_segment(&self.config.dir, self.index)?;
+        self.segment_len = 0;
+
+        self.trim()
+    }
+
+    /// Delete the oldest finished segments until the journal is within its retention limits
+    fn trim(&mut self) -> Result<()> {
+        self.trimmed_at = Instant::now();
+
+        let mut total: u64 = segments(&self.config.dir)?
+            .iter()
+            .map(|(_, path)| fs::metadata(path).map(|m| m.len()).unwrap_or(0))
+            .sum();
+
+        for (index, path) in segments(&self.config.dir)? {
+            // never delete the segment being appended to
+            if index == self.index {
+                break;
+            }
+
+            let metadata = fs::metadata(&path)?;
+            let expired = match self.config.max_age {
+                Some(max_age) => metadata
+                    .modified()
+                    .ok()
+                    .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+                    .is_some_and(|age| age > max_age),
+                None => false,
+            };
+            let oversized = self.config.max_size.is_some_and(|max| total > max);
+
+            if !(expired || oversized) {
+                break;
+            }
+
+            debug!("delete journal segment {}", path.display());
+            fs::remove_file(&path)?;
+            total -= metadata.len();
+        }
+
+        Ok(())
+    }
+
+    /// Read every message in the journal in `dir`, oldest first
+    ///
+    /// The messages are read as they are iterated over rather than all at once.
+    pub fn read<P: AsRef<Path>>(dir: P) -> Result<JournalEntries> {
+        Ok(JournalEntries {
+            segments: segments(dir.as_ref())?.into_iter(),
+            current: None,
+        })
+    }
+}
+
+/// The messages in a journal, oldest first, from [`Journal::read`]
+///
+/// Lines which can't be parsed, such as one torn by a crash part way through an append or
+/// one which isn't valid UTF-8, are skipped.
+#[derive(Debug)]
+pub struct JournalEntries {
+    /// The segments still to be read
+    segments: std::vec::IntoIter<(u64, PathBuf)>,
+    /// The segment being read, and its lines
+    current: Option<(PathBuf, Split<BufReader<File>>)>,
+}
+
+impl Iterator for JournalEntries {
+    type Item = Result<Envelope>;
+
+    fn next(&mut self) -> Option<Result<Envelope>> {
+        loop {
+            let (path, lines) = match &mut self.current {
+                Some(current) => current,
+                None => {
+                    let (_, path) = self.segments.next()?;
+                    debug!("read journal segment {}", path.display());
+                    let lines = match File::open(&path) {
+                        Ok(file) => BufReader::new(file).split(b'\n'),
+                        Err(e) => return Some(Err(e.into())),
+                    };
+                    self.current.insert((path, lines))
+                }
+            };
+
+            match lines.next() {
+                Some(Ok(line)) => match serde_json::from_slice(&line) {
+                    Ok(envelope) => return Some(Ok(envelope)),
+                    Err(e) => warn!("skipping bad entry in {}: {}", path.display(), e),
+                },
+                Some(Err(e)) => return Some(Err(e.into())),
+                None => self.current = None,
+            }
+        }
+    }
+}
+
+/// The segments in `dir`, ordered by index
+fn segments(dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let mut segments = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some(SEGMENT_EXTENSION) {
+            continue;
+        }
+
+        if let Some(index) = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse().ok())
+        {
+            segments.push((index, path));
+        }
+    }
+
+    segments.sort();
+
+    Ok(segments)
+}
+
+/// The path of the segment with the given index
+fn segment_path(dir: &Path, index: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", index, SEGMENT_EXTENSION))
+}
+
+/// Open the segment with the given index for appending
+fn open_segment(dir: &Path, index: u64) -> Result<File> {
+    Ok(OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(segment_path(dir, index))?)
+}
+
+/// Truncate the segment at `path` after its last complete line
+///
+/// A message torn by a crash part way through an append would otherwise run into the next
+/// message appended, losing both.
+fn truncate_torn_tail(path: &Path) -> Result<()> {
+    let mut file = match OpenOptions::new().read(true).write(true).open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let len = file.metadata()?.len();
+
+    // search backwards for the last newline, a chunk at a time
+    let mut end = len;
+    let mut buf = [0; 4096];
+    while end > 0 {
+        let start = end.saturating_sub(buf.len() as u64);
+        let chunk = &mut buf[..(end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(chunk)?;
+
+        if let Some(newline) = chunk.iter().rposition(|&b| b == b'\n') {
+            end = start + newline as u64 + 1;
+            break;
+        }
+        end = start;
+    }
+
+    if end < len {
+        warn!(
+            "truncating {} bytes torn from the end of {}",
+            len - end,
+            path.display()
+        );
+        file.set_len(end)?;
+        file.sync_data()?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::message::Message;
+
+    fn text(id: u64) -> Envelope {
+        Envelope::request(id, Message::Text(format!("message {}", id)))
+    }
+
+    fn ids<P: AsRef<Path>>(dir: P) -> Vec<u64> {
+        Journal::read(dir)
+            .unwrap()
+            .map(|envelope| envelope.unwrap().id.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn parse_sync_policy() {
+        assert_eq!("always".parse::<SyncPolicy>().unwrap(), SyncPolicy::Always);
+        assert_eq!("never".parse::<SyncPolicy>().unwrap(), SyncPolicy::Never);
+        assert_eq!(
+            "every:10".parse::<SyncPolicy>().unwrap(),
+            SyncPolicy::Every(10)
+        );
+        for s in &["", "every:", "every:0", "every:-1", "sometimes"] {
+            assert!(s.parse::<SyncPolicy>().is_err(), "parsed {:?}", s);
+        }
+    }
+
+    #[test]
+    fn read_returns_appended_messages_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut journal = Journal::open(JournalConfig::new(dir.path())).unwrap();
+        for id in 1..=3 {
+            journal.append(&text(id)).unwrap();
+        }
+
+        assert_eq!(ids(dir.path()), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn reopening_appends_to_the_newest_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        Journal::open(JournalConfig::new(dir.path()))
+            .unwrap()
+            .append(&text(1))
+            .unwrap();
+        Journal::open(JournalConfig::new(dir.path()))
+            .unwrap()
+            .append(&text(2))
+            .unwrap();
+
+        assert_eq!(segments(dir.path()).unwrap().len(), 1);
+        assert_eq!(ids(dir.path()), vec![1, 2]);
+    }
+
+    #[test]
+    fn full_segments_are_rotated() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = JournalConfig {
+            segment_size: 1,
+            ..JournalConfig::new(dir.path())
+        };
+        let mut journal = Journal::open(config).unwrap();
+        for id in 1..=3 {
+            journal.append(&text(id)).unwrap();
+        }
+
+        let indices: Vec<u64> = segments(dir.path())
+            .unwrap()
+            .into_iter()
+            .map(|(index, _)| index)
+            .collect();
+        assert_eq!(indices, vec![0, 1, 2]);
+        assert_eq!(ids(dir.path()), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn oldest_segments_are_trimmed_to_the_size_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let segment_size = text(1).encoded_len().unwrap() as u64;
+        let config = JournalConfig {
+            segment_size,
+            max_size: Some(2 * segment_size),
+            ..JournalConfig::new(dir.path())
+        };
+        let mut journal = Journal::open(config).unwrap();
+        for id in 1..=5 {
+            journal.append(&text(id)).unwrap();
+        }
+
+        // the limit is checked when a segment is started, before the message which fills it
+        assert_eq!(ids(dir.path()), vec![3, 4, 5]);
+    }
+
+    #[test]
+    fn expired_segments_are_trimmed_while_appending() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = JournalConfig {
+            segment_size: 3 * text(1).encoded_len().unwrap() as u64,
+            max_age: Some(Duration::from_secs(60)),
+            ..JournalConfig::new(dir.path())
+        };
+        let mut journal = Journal::open(config).unwrap();
+        for id in 1..=4 {
+            journal.append(&text(id)).unwrap();
+        }
+
+        // the first segment, holding 1 to 3, was finished long ago
+        let hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        File::options()
+            .append(true)
+            .open(segment_path(dir.path(), 0))
+            .unwrap()
+            .set_modified(hour_ago)
+            .unwrap();
+
+        // it outlives its age until the next trim is due
+        journal.append(&text(5)).unwrap();
+        assert_eq!(ids(dir.path()), vec![1, 2, 3, 4, 5]);
+
+        // which comes before the second segment is full
+        journal.trimmed_at = Instant::now() - TRIM_INTERVAL;
+        journal.append(&text(6)).unwrap();
+        assert_eq!(segments(dir.path()).unwrap().len(), 1);
+        assert_eq!(ids(dir.path()), vec![4, 5, 6]);
+    }
+
+    #[test]
+    fn the_segment_being_appended_to_is_never_trimmed() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = JournalConfig {
+            max_size: Some(0),
+            max_age: Some(Duration::from_secs(0)),
+            ..JournalConfig::new(dir.path())
+        };
+        let mut journal = Journal::open(config.clone()).unwrap();
+        journal.append(&text(1)).unwrap();
+        drop(journal);
+
+        Journal::open(config).unwrap();
+        assert_eq!(ids(dir.path()), vec![1]);
+    }
+
+    #[test]
+    fn opening_truncates_a_torn_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut journal = Journal::open(JournalConfig::new(dir.path())).unwrap();
+        journal.append(&text(1)).unwrap();
+        drop(journal);
+
+        // a crash part way through appending the second message
+        let path = segment_path(dir.path(), 0);
+        let mut torn = Vec::new();
+        text(2).write(&mut torn).unwrap();
+        let mut segment = OpenOptions::new().append(true).open(&path).unwrap();
+        io::Write::write_all(&mut segment, &torn[..torn.len() / 2]).unwrap();
+        drop(segment);
+
+        let mut journal = Journal::open(JournalConfig::new(dir.path())).unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().len(),
+            text(1).encoded_len().unwrap() as u64
+        );
+        journal.append(&text(3)).unwrap();
+
+        assert_eq!(ids(dir.path()), vec![1, 3]);
+    }
+
+    #[test]
+    fn opening_empties_a_segment_holding_only_a_torn_message() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = segment_path(dir.path(), 0);
+        fs::write(&path, br#"{"id":1,"message":{"Te"#).unwrap();
+
+        let mut journal = Journal::open(JournalConfig::new(dir.path())).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+        journal.append(&text(2)).unwrap();
+
+        assert_eq!(ids(dir.path()), vec![2]);
+    }
+
+    #[test]
+    fn read_skips_bad_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut segment = Vec::new();
+        text(1).write(&mut segment).unwrap();
+        segment.extend_from_slice(b"\xff\xfe not utf-8\n");
+        segment.extend_from_slice(b"{\"not\": \"an envelope\"}\n");
+        text(2).write(&mut segment).unwrap();
+        segment.extend_from_slice(br#"{"id":3,"mess"#);
+        fs::write(segment_path(dir.path(), 0), segment).unwrap();
+
+        assert_eq!(ids(dir.path()), vec![1, 2]);
+    }
+
+    #[test]
+    fn read_ignores_files_which_arent_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        Journal::open(JournalConfig::new(dir.path()))
+            .unwrap()
+            .append(&text(1))
+            .unwrap();
+        fs::write(dir.path().join("notes.txt"), "not a segment\n").unwrap();
+        fs::write(dir.path().join("latest.journal"), "not a segment\n").unwrap();
+
+        assert_eq!(ids(dir.path()), vec![1]);
+    }
+}
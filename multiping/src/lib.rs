@@ -1,19 +1,52 @@
 //! multiping module
 
 #[macro_use]
-extern crate log;
+extern crate tracing;
 
+mod admin;
+mod admission;
+mod auth;
+mod ban;
 mod client;
+mod command;
 mod connection;
+mod delivery;
 mod error;
+mod event;
+mod handle;
+mod history;
+mod journal;
+mod listener;
+mod mailbox;
 mod message;
+mod ratelimit;
+mod reload;
 mod server;
+mod stats;
+mod timeout;
 
-pub use client::Client;
-pub use connection::Connection;
-pub use error::{Error, Result};
-pub use message::Message;
-pub use server::Server;
+pub use admin::{AdminReply, AdminRequest};
+pub use admission::{AdmissionConfig, Cidr};
+pub use auth::{Principal, TokenStore};
+pub use ban::{Ban, BanList, BanTarget};
+pub use client::{Client, ClientConfig, SeenWindow};
+pub use command::{Caller, Commands};
+pub use connection::{Connection, ConnectionId, ConnectionInfo};
+pub use error::{Error, ErrorCode, Result};
+pub use event::ServerEvent;
+pub use handle::ServerHandle;
+pub use history::HistoryConfig;
+pub use journal::{Journal, JournalConfig, JournalEntries, SyncPolicy};
+pub use listener::{ListenAddr, ListenerConfig, Peer, Stream};
+pub use mailbox::MailboxConfig;
+pub use message::{
+    DisconnectReason, Envelope, ErrorPayload, Message, MessageId, DEFAULT_MAX_MESSAGE_SIZE,
+};
+pub use ratelimit::{RateLimit, RateLimitAction, RateLimitConfig, RateLimitStats};
+pub use reload::{ReloadHandle, ReloadReport, ReloadedConfig};
+pub use server::{Input, Server, ServerConfig};
+pub use stats::{Histogram, ServerStats, StatsHandle};
+pub use timeout::{Timeouts, DEFAULT_TIMEOUT_WARNING};
 
 #[cfg(test)]
 mod tests {}
@@ -0,0 +1,285 @@
+//! The addresses a server listens on, and the streams its connections run over
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::timeout::Timeouts;
+
+/// The prefix which marks a [`ListenAddr`] as a Unix socket path
+const UNIX_PREFIX: &str = "unix:";
+
+/// An address to listen for connections on
+///
+/// Written as a TCP `host:port`, e.g. `0.0.0.0:3000` or `[::]:3000`, or a Unix socket path
+/// prefixed with `unix:`, e.g. `unix:/run/multiping.sock`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddr {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ListenAddr> {
+        match s.strip_prefix(UNIX_PREFIX) {
+            Some("") => Err(Error::InvalidConfig(format!(
+                "expected a socket path after {}",
+                UNIX_PREFIX
+            ))),
+            Some(path) => Ok(ListenAddr::Unix(PathBuf::from(path))),
+            None if s.contains(':') => Ok(ListenAddr::Tcp(s.to_string())),
+            None => Err(Error::InvalidConfig(format!(
+                "expected <host>:<port> or {}<path>, got {}",
+                UNIX_PREFIX, s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+/// Settings for one of the addresses a [`crate::Server`] listens on
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListenerConfig {
+    /// Where to listen
+    pub addr: ListenAddr,
+
+    /// Whether connections must authenticate when the server has tokens, or are admitted
+    /// straight away, e.g. on a Unix socket only trusted local users can reach
+    pub require_auth: bool,
+
+    /// How long connections may stay open
+    pub timeouts: Timeouts,
+}
+
+impl ListenerConfig {
+    /// Listen on `addr`, requiring authentication if the server has tokens and without
+    /// timing out connections
+    pub fn new(addr: ListenAddr) -> ListenerConfig {
+        ListenerConfig {
+            addr,
+            require_auth: true,
+            timeouts: Timeouts::default(),
+        }
+    }
+}
+
+/// A bound socket accepting connections
+#[derive(Debug)]
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Bind to `addr`, replacing a Unix socket left behind by a previous run
+    pub(crate) fn bind(addr: &ListenAddr) -> Result<Listener> {
+        match addr {
+            ListenAddr::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr)?)),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                use std::os::unix::fs::FileTypeExt;
+
+                if let Ok(metadata) = std::fs::symlink_metadata(path) {
+                    if metadata.file_type().is_socket() {
+                        debug!("remove stale socket {}", path.display());
+                        std::fs::remove_file(path)?;
+                    }
+                }
+                Ok(Listener::Unix(UnixListener::bind(path)?))
+            }
+            #[cfg(not(unix))]
+            ListenAddr::Unix(_) => Err(Error::InvalidConfig(
+                "unix sockets are not supported on this platform".to_string(),
+            )),
+        }
+    }
+
+    /// The address the listener is bound to, with the port chosen if it was bound to port 0
+    pub(crate) fn local_addr(&self) -> io::Result<ListenAddr> {
+        match self {
+            Listener::Tcp(listener) => Ok(ListenAddr::Tcp(listener.local_addr()?.to_string())),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener
+                .local_addr()?
+                .as_pathname()
+                .map(|path| ListenAddr::Unix(path.to_path_buf()))
+                .ok_or_else(|| io::Error::other("unnamed unix socket")),
+        }
+    }
+
+    /// Wait for the next connection
+    pub(crate) fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(stream, _)| Stream::Tcp(stream)),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.accept().map(|(stream, _)| Stream::Unix(stream)),
+        }
+    }
+}
+
+/// Connect to the listener bound to `addr`, so that a thread blocked accepting connections
+/// on it wakes up
+pub(crate) fn wake(addr: &ListenAddr) -> io::Result<()> {
+    match addr {
+        ListenAddr::Tcp(addr) => {
+            let mut addr: SocketAddr = addr
+                .parse()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            // a listener on every interface can be reached on loopback
+            if addr.ip().is_unspecified() {
+                let loopback = match addr {
+                    SocketAddr::V4(_) => IpAddr::from(Ipv4Addr::LOCALHOST),
+                    SocketAddr::V6(_) => IpAddr::from(Ipv6Addr::LOCALHOST),
+                };
+                addr.set_ip(loopback);
+            }
+            TcpStream::connect(addr).map(drop)
+        }
+        #[cfg(unix)]
+        ListenAddr::Unix(path) => UnixStream::connect(path).map(drop),
+        #[cfg(not(unix))]
+        ListenAddr::Unix(_) => Ok(()),
+    }
+}
+
+/// The other end of a connection
+///
+/// Unix socket clients have no address, so the rules and limits which apply to addresses
+/// don't apply to them: `allow` and `deny` ranges, address bans, the connection limit per
+/// address and the per-address rate limit. The limits per connection, the server's
+/// connection limit and bans of principals still do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum Peer {
+    /// A TCP client, by its address
+    Tcp(SocketAddr),
+    /// A client of a Unix socket
+    Unix,
+}
+
+impl Peer {
+    /// The client's IP address, if it has one
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Peer::Tcp(addr) => Some(addr.ip()),
+            Peer::Unix => None,
+        }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Peer::Tcp(addr) => write!(f, "{}", addr),
+            Peer::Unix => write!(f, "unix socket"),
+        }
+    }
+}
+
+/// A stream a connection sends and receives messages over
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /// The other end of the stream
+    ///
+    /// IPv4 clients of a dual-stack IPv6 listener are reported by their IPv4 address rather
+    /// than as `::ffff:a.b.c.d`, so that IPv4 admission ranges, bans and per-IP limits apply
+    /// to them.
+    pub fn peer(&self) -> io::Result<Peer> {
+        match self {
+            Stream::Tcp(stream) => stream
+                .peer_addr()
+                .map(|addr| Peer::Tcp(SocketAddr::new(addr.ip().to_canonical(), addr.port()))),
+            #[cfg(unix)]
+            Stream::Unix(_) => Ok(Peer::Unix),
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(how),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl From<TcpStream> for Stream {
+    fn from(stream: TcpStream) -> Stream {
+        Stream::Tcp(stream)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixStream> for Stream {
+    fn from(stream: UnixStream) -> Stream {
+        Stream::Unix(stream)
+    }
+}
@@ -0,0 +1,175 @@
+//! Per-identity mailboxes holding direct messages for principals which aren't connected
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::auth::Principal;
+use crate::message::Envelope;
+
+/// How many messages are held for each offline principal
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MailboxConfig {
+    /// The number of messages to hold per principal, or 0 to hold none
+    pub capacity: usize,
+    /// How long to hold each message, or `None` to hold it until it is delivered or
+    /// pushed out by newer ones
+    pub ttl: Option<Duration>,
+}
+
+/// The messages waiting for each offline principal
+#[derive(Debug)]
+pub struct Mailboxes {
+    config: MailboxConfig,
+    boxes: HashMap<Principal, VecDeque<(Instant, Envelope)>>,
+}
+
+impl Mailboxes {
+    pub fn new(config: MailboxConfig) -> Mailboxes {
+        Mailboxes {
+            config,
+            boxes: HashMap::new(),
+        }
+    }
+
+    /// Hold a message for `principal`, evicting the oldest if their mailbox is full
+    ///
+    /// Returns whether the message was held.
+    pub fn deposit(&mut self, principal: &str, envelope: Envelope) -> bool {
+        if self.config.capacity == 0 {
+            return false;
+        }
+
+        let mailbox = self.boxes.entry(principal.to_string()).or_default();
+        expire(mailbox, self.config.ttl);
+
+        if mailbox.len() == self.config.capacity {
+            warn!(
+                "mailbox for {} is full, dropping its oldest message",
+                principal
+            );
+            mailbox.pop_front();
+        }
+        mailbox.push_back((Instant::now(), envelope));
+
+        true
+    }
+
+    /// Take the messages held for `principal`, oldest first
+    pub fn collect(&mut self, principal: &str) -> Vec<Envelope> {
+        let mut mailbox = match self.boxes.remove(principal) {
+            Some(mailbox) => mailbox,
+            None => return Vec::new(),
+        };
+        expire(&mut mailbox, self.config.ttl);
+
+        mailbox.into_iter().map(|(_, envelope)| envelope).collect()
+    }
+}
+
+/// Forget messages which have been held for longer than `ttl`
+fn expire(mailbox: &mut VecDeque<(Instant, Envelope)>, ttl: Option<Duration>) {
+    if let Some(ttl) = ttl {
+        while let Some((deposited, _)) = mailbox.front() {
+            if deposited.elapsed() <= ttl {
+                break;
+            }
+            mailbox.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::message::Message;
+
+    fn direct(id: u64) -> Envelope {
+        Envelope::request(
+            id,
+            Message::Direct {
+                to: "bob".to_string(),
+                text: format!("message {}", id),
+            },
+        )
+    }
+
+    fn ids(envelopes: Vec<Envelope>) -> Vec<u64> {
+        envelopes
+            .into_iter()
+            .map(|envelope| envelope.id.unwrap())
+            .collect()
+    }
+
+    fn mailboxes(capacity: usize, ttl: Option<Duration>) -> Mailboxes {
+        Mailboxes::new(MailboxConfig { capacity, ttl })
+    }
+
+    #[test]
+    fn messages_are_collected_oldest_first_and_only_once() {
+        let mut mailboxes = mailboxes(10, None);
+        for id in 1..=3 {
+            assert!(mailboxes.deposit("bob", direct(id)));
+        }
+
+        assert_eq!(ids(mailboxes.collect("bob")), vec![1, 2, 3]);
+        assert!(mailboxes.collect("bob").is_empty());
+    }
+
+    #[test]
+    fn each_principal_has_a_mailbox_of_its_own() {
+        let mut mailboxes = mailboxes(2, None);
+        mailboxes.deposit("bob", direct(1));
+        mailboxes.deposit("carol", direct(2));
+        mailboxes.deposit("carol", direct(3));
+        mailboxes.deposit("carol", direct(4));
+
+        // carol's full mailbox doesn't push out bob's messages
+        assert_eq!(ids(mailboxes.collect("bob")), vec![1]);
+        assert_eq!(ids(mailboxes.collect("carol")), vec![3, 4]);
+        assert!(mailboxes.collect("dave").is_empty());
+    }
+
+    #[test]
+    fn overflow_drops_the_oldest_messages() {
+        let mut mailboxes = mailboxes(3, None);
+        for id in 1..=5 {
+            // the new message is always held
+            assert!(mailboxes.deposit("bob", direct(id)));
+        }
+
+        assert_eq!(ids(mailboxes.collect("bob")), vec![3, 4, 5]);
+    }
+
+    #[test]
+    fn zero_capacity_holds_nothing() {
+        let mut mailboxes = mailboxes(0, None);
+
+        assert!(!mailboxes.deposit("bob", direct(1)));
+        assert!(mailboxes.collect("bob").is_empty());
+    }
+
+    #[test]
+    fn messages_expire_after_their_ttl() {
+        let mut mailboxes = mailboxes(10, Some(Duration::from_secs(60)));
+        mailboxes.deposit("bob", direct(1));
+        mailboxes.deposit("bob", direct(2));
+
+        // as if the first had been deposited a minute and a half ago
+        mailboxes.boxes.get_mut("bob").unwrap()[0].0 = Instant::now() - Duration::from_secs(90);
+
+        assert_eq!(ids(mailboxes.collect("bob")), vec![2]);
+    }
+
+    #[test]
+    fn expired_messages_make_room_before_overflow() {
+        let mut mailboxes = mailboxes(2, Some(Duration::from_secs(60)));
+        mailboxes.deposit("bob", direct(1));
+        mailboxes.deposit("bob", direct(2));
+        mailboxes.boxes.get_mut("bob").unwrap()[0].0 = Instant::now() - Duration::from_secs(90);
+
+        // message 1 has expired, so nothing live is dropped for message 3
+        mailboxes.deposit("bob", direct(3));
+        assert_eq!(ids(mailboxes.collect("bob")), vec![2, 3]);
+    }
+}
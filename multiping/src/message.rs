@@ -1,9 +1,96 @@
 use std::fmt;
-use std::io::{BufRead, BufReader, Read, Write};
+use std::io::{self, BufRead, Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
-use crate::Result;
+use crate::admin::{AdminReply, AdminRequest};
+use crate::auth::Principal;
+use crate::error::ErrorCode;
+use crate::reload::ReloadReport;
+use crate::stats::ServerStats;
+use crate::{Error, Result};
+
+/// The default limit on the size of a single encoded message, in bytes
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// The identifier of a message
+pub type MessageId = u64;
+
+/// The current time in milliseconds since the unix epoch, as used for [`Envelope::timestamp`]
+pub fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// The body of a [`Message::Error`]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ErrorPayload {
+    /// What kind of error occurred
+    pub code: ErrorCode,
+    /// A human readable description of the error
+    pub message: String,
+    /// The message which caused the error, if it is known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<MessageId>,
+}
+
+impl From<&Error> for ErrorPayload {
+    fn from(err: &Error) -> ErrorPayload {
+        ErrorPayload {
+            code: err.code(),
+            message: err.to_string(),
+            reference: None,
+        }
+    }
+}
+
+impl fmt::Display for ErrorPayload {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+/// Why a connection was closed, sent to the client in a [`Message::Disconnect`]
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize,
+)]
+pub enum DisconnectReason {
+    /// The client asked to disconnect
+    #[default]
+    ClientRequested,
+    /// The server is shutting down
+    ServerShutdown,
+    /// The client was silent for too long
+    IdleTimeout,
+    /// The client was connected for longer than the server allows
+    SessionExpired,
+    /// An admin removed the client from the server
+    Kicked,
+    /// The client, or its address, is banned
+    Banned,
+    /// The client sent malformed, oversized or unexpected messages
+    ProtocolError,
+    /// The client failed to authenticate
+    AuthenticationFailed,
+    /// The client's principal lost its token when the server reloaded
+    TokenRevoked,
+    /// The client exceeded a rate limit
+    RateLimited,
+    /// The client didn't read its messages quickly enough
+    SlowConsumer,
+    /// The stream broke or was closed without a disconnect message
+    ConnectionLost,
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
 
 /// A message that can be sent and received over a stream#
 ///
@@ -11,16 +98,146 @@ use crate::Result;
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum Message {
     Ping,
+    Pong,
     Text(String),
     InvalidMessage,
-    Disconnect,
-    Error(String),
+    Disconnect {
+        #[serde(default)]
+        reason: DisconnectReason,
+    },
+    Error(ErrorPayload),
+    Auth {
+        token: String,
+    },
+    Authenticated(String),
+    Command {
+        name: String,
+        #[serde(default)]
+        args: Value,
+    },
+    CommandResult(Value),
+    /// Requests a replay of the retained messages routed at or after `since`
+    /// (a [`timestamp`]), or all of them if `since` is `None`
+    History {
+        #[serde(default)]
+        since: Option<u64>,
+    },
+    /// Replies to [`Message::History`] once the given number of messages have been replayed
+    Replayed(usize),
+    /// Text for a single principal, held until they next authenticate if they're offline
+    Direct {
+        to: Principal,
+        text: String,
+    },
+    /// Acknowledges receipt of the message with the given id, which was sent with
+    /// [`Envelope::ack`] set
+    Ack(MessageId),
+    /// Requests a snapshot of the server's statistics
+    Stats,
+    /// Replies to [`Message::Stats`]
+    StatsReport(Box<ServerStats>),
+    /// Asks the server to reload its config, which only its admins may do
+    Reload,
+    /// Grants an authenticated connection admin rights, if `token` is an admin token for
+    /// the principal it authenticated as
+    AdminAuth {
+        token: String,
+    },
+    /// Replies to [`Message::AdminAuth`] with the principal now acting as an admin
+    AdminAuthenticated(Principal),
+    /// Replies to [`Message::Reload`] with the settings which changed
+    Reloaded(ReloadReport),
+    /// Asks the server to carry out an administrative operation, which only its admins may do
+    Admin(AdminRequest),
+    /// Replies to [`Message::Admin`] once the operation is done
+    AdminReply(AdminReply),
+    /// An announcement from the server, sent to every authenticated client
+    Notice(String),
+    /// Warns the client it will be disconnected in `seconds` unless it becomes active,
+    /// which only helps if `reason` is [`DisconnectReason::IdleTimeout`]
+    DisconnectWarning {
+        reason: DisconnectReason,
+        seconds: u64,
+    },
 }
 
-impl Message {
-    /// Encode the message as JSON and send it down the stream
+/// A [`Message`] along with the ids used to match replies to requests
+///
+/// This is the unit which is written to and read from streams.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Envelope {
+    /// The id of this message, chosen by its sender
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<MessageId>,
+
+    /// The id of the request this message is a reply to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<MessageId>,
+
+    /// The principal which sent the message, set by the server when it routes the message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender: Option<Principal>,
+
+    /// When the server routed the message, as a [`timestamp`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+
+    /// Whether this is a replay of an earlier message rather than live traffic
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub replay: bool,
+
+    /// Whether receivers must acknowledge this message with a [`Message::Ack`], in which
+    /// case the server retransmits it until they do and it may be received more than once
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub ack: bool,
+
+    pub message: Message,
+}
+
+fn is_false(b: &bool) -> bool {
+    !b
+}
+
+impl Envelope {
+    /// Wrap a message which isn't a request or a reply
+    pub fn new(message: Message) -> Envelope {
+        Envelope {
+            id: None,
+            reply_to: None,
+            sender: None,
+            timestamp: None,
+            replay: false,
+            ack: false,
+            message,
+        }
+    }
+
+    /// Wrap a request, whose reply will refer to `id`
+    pub fn request(id: MessageId, message: Message) -> Envelope {
+        Envelope {
+            id: Some(id),
+            ..Envelope::new(message)
+        }
+    }
+
+    /// Wrap a reply to the request with id `reply_to`, if the request had one
+    pub fn reply(reply_to: Option<MessageId>, message: Message) -> Envelope {
+        Envelope {
+            reply_to,
+            ..Envelope::new(message)
+        }
+    }
+
+    /// Wrap an error reply caused by the request with id `reply_to`, if the request had one
+    pub fn error(reply_to: Option<MessageId>, err: &Error) -> Envelope {
+        let mut payload = ErrorPayload::from(err);
+        payload.reference = reply_to;
+        Envelope::reply(reply_to, Message::Error(payload))
+    }
+
+    /// Encode the envelope as JSON and send it down the stream
     pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
-        debug!("write message {}", self);
+        debug!("write message {}", self.message);
 
         // Serialize the message as JSON
         debug!("serialize message");
@@ -38,35 +255,224 @@ impl Message {
         Ok(())
     }
 
-    /// Try and construct a message from JSON read from a stream
-    pub fn recv<R: Read>(reader: &mut R) -> Result<Message> {
-        debug!("parse message from reader");
+    /// The number of bytes the envelope occupies on the wire
+    pub fn encoded_len(&self) -> Result<usize> {
+        // the JSON plus its terminating newline
+        Ok(serde_json::to_vec(self)?.len() + 1)
+    }
 
-        let mut buf = BufReader::new(reader);
+    /// Try and construct a message from a line of JSON read from a stream
+    ///
+    /// Fails with [`Error::MessageTooLarge`] if the line is longer than `max_size` bytes,
+    /// without reading more than `max_size + 1` bytes of it.
+    pub fn recv<R: BufRead>(reader: &mut R, max_size: usize) -> Result<Envelope> {
+        debug!("parse message from reader");
 
         // Read a line of JSON from the stream
-        let mut json = String::new();
+        let mut json = Vec::new();
         debug!("read line");
-        buf.read_line(&mut json)?;
+        let len = reader
+            .by_ref()
+            .take(max_size as u64 + 1)
+            .read_until(b'\n', &mut json)?;
+
+        if len == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "stream closed").into());
+        }
+
+        if json.last() != Some(&b'\n') && len > max_size {
+            warn!("message exceeds the maximum size of {} bytes", max_size);
+            return Err(Error::MessageTooLarge(max_size));
+        }
 
         // Deserialize message from JSON
         debug!("deserialize");
-        let msg = serde_json::from_str(&json)?;
+        let envelope: Envelope = serde_json::from_slice(&json)?;
+
+        debug!("message read sucessfully: {}", envelope.message);
 
-        debug!("message read sucessfully: {}", msg);
+        Ok(envelope)
+    }
+}
 
-        Ok(msg)
+impl From<Message> for Envelope {
+    fn from(message: Message) -> Envelope {
+        Envelope::new(message)
+    }
+}
+
+/// What a token is replaced with by [`Message::redacted`]
+const REDACTED: &str = "<redacted>";
+
+impl Message {
+    /// A copy of the message with any token it carries replaced, safe to hand to code which
+    /// shouldn't see credentials
+    pub fn redacted(&self) -> Message {
+        match self {
+            Message::Auth { .. } => Message::Auth {
+                token: REDACTED.to_string(),
+            },
+            Message::AdminAuth { .. } => Message::AdminAuth {
+                token: REDACTED.to_string(),
+            },
+            msg => msg.clone(),
+        }
+    }
+
+    /// The name of the message's variant, as used in its encoding
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Message::Ping => "Ping",
+            Message::Pong => "Pong",
+            Message::Text(_) => "Text",
+            Message::InvalidMessage => "InvalidMessage",
+            Message::Disconnect { .. } => "Disconnect",
+            Message::Error(_) => "Error",
+            Message::Auth { .. } => "Auth",
+            Message::Authenticated(_) => "Authenticated",
+            Message::Command { .. } => "Command",
+            Message::CommandResult(_) => "CommandResult",
+            Message::History { .. } => "History",
+            Message::Replayed(_) => "Replayed",
+            Message::Direct { .. } => "Direct",
+            Message::Ack(_) => "Ack",
+            Message::Stats => "Stats",
+            Message::StatsReport(_) => "StatsReport",
+            Message::Reload => "Reload",
+            Message::Reloaded(_) => "Reloaded",
+            Message::AdminAuth { .. } => "AdminAuth",
+            Message::AdminAuthenticated(_) => "AdminAuthenticated",
+            Message::Admin(_) => "Admin",
+            Message::AdminReply(_) => "AdminReply",
+            Message::Notice(_) => "Notice",
+            Message::DisconnectWarning { .. } => "DisconnectWarning",
+        }
     }
 }
 
 impl fmt::Display for Message {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Message::Disconnect => write!(f, "Disconnect"),
+            Message::Disconnect { reason } => write!(f, "Disconnect: {}", reason),
             Message::InvalidMessage => write!(f, "Invalid message"),
             Message::Ping => write!(f, "Ping"),
+            Message::Pong => write!(f, "Pong"),
             Message::Text(s) => write!(f, "'{}'", s),
             Message::Error(e) => write!(f, "error: {}", e),
+            Message::Auth { .. } => write!(f, "Auth"),
+            Message::Authenticated(principal) => write!(f, "Authenticated as {}", principal),
+            Message::Command { name, args } => write!(f, "Command {}({})", name, args),
+            Message::CommandResult(result) => write!(f, "Command result: {}", result),
+            Message::History { since: Some(since) } => write!(f, "History since {}", since),
+            Message::History { since: None } => write!(f, "History"),
+            Message::Replayed(count) => write!(f, "Replayed {} messages", count),
+            Message::Direct { to, text } => write!(f, "'{}' to {}", text, to),
+            Message::Ack(id) => write!(f, "Ack {}", id),
+            Message::Stats => write!(f, "Stats"),
+            Message::StatsReport(_) => write!(f, "Stats report"),
+            Message::Reload => write!(f, "Reload"),
+            Message::Reloaded(report) => write!(f, "Reloaded: {}", report),
+            Message::AdminAuth { .. } => write!(f, "Admin auth"),
+            Message::AdminAuthenticated(principal) => {
+                write!(f, "Authenticated as admin {}", principal)
+            }
+            Message::Admin(request) => write!(f, "Admin: {}", request),
+            Message::AdminReply(reply) => write!(f, "Admin reply: {}", reply),
+            Message::Notice(text) => write!(f, "Notice: '{}'", text),
+            Message::DisconnectWarning { reason, seconds } => {
+                write!(f, "Disconnecting in {} seconds: {}", seconds, reason)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_SIZE: usize = 64;
+
+    /// The JSON of a text message with no text
+    const EMPTY_TEXT: &str = r#"{"message":{"Text":""}}"#;
+
+    /// A line holding a text message whose JSON is exactly `len` bytes, before the newline
+    fn line_of(len: usize) -> Vec<u8> {
+        let text = "a".repeat(len - EMPTY_TEXT.len());
+        format!("{{\"message\":{{\"Text\":\"{}\"}}}}\n", text).into_bytes()
+    }
+
+    fn recv(bytes: &[u8]) -> Result<Envelope> {
+        Envelope::recv(&mut io::Cursor::new(bytes), MAX_SIZE)
+    }
+
+    #[test]
+    fn recv_accepts_a_message_of_the_maximum_size() {
+        let line = line_of(MAX_SIZE);
+        assert_eq!(line.len(), MAX_SIZE + 1);
+
+        match recv(&line).unwrap().message {
+            Message::Text(text) => assert_eq!(text.len(), MAX_SIZE - EMPTY_TEXT.len()),
+            msg => panic!("unexpected message {}", msg),
+        }
+    }
+
+    #[test]
+    fn recv_rejects_a_message_one_byte_over_the_maximum_size() {
+        match recv(&line_of(MAX_SIZE + 1)) {
+            Err(Error::MessageTooLarge(max)) => assert_eq!(max, MAX_SIZE),
+            other => panic!("unexpected {:?}", other),
         }
     }
+
+    #[test]
+    fn recv_reads_no_more_than_one_byte_past_the_maximum_size() {
+        let mut reader = io::Cursor::new(line_of(MAX_SIZE * 2));
+        assert!(matches!(
+            Envelope::recv(&mut reader, MAX_SIZE),
+            Err(Error::MessageTooLarge(_))
+        ));
+        assert_eq!(reader.position(), MAX_SIZE as u64 + 1);
+    }
+
+    #[test]
+    fn recv_accepts_a_final_line_without_a_newline() {
+        let mut line = line_of(MAX_SIZE);
+        line.pop();
+        assert!(matches!(recv(&line).unwrap().message, Message::Text(_)));
+    }
+
+    #[test]
+    fn recv_reads_one_line_at_a_time() {
+        let mut bytes = line_of(30);
+        bytes.extend_from_slice(b"{\"id\":7,\"message\":\"Ping\"}\n");
+        let mut reader = io::Cursor::new(bytes);
+
+        assert!(matches!(
+            Envelope::recv(&mut reader, MAX_SIZE).unwrap().message,
+            Message::Text(_)
+        ));
+        let ping = Envelope::recv(&mut reader, MAX_SIZE).unwrap();
+        assert_eq!(ping.id, Some(7));
+        assert!(matches!(ping.message, Message::Ping));
+    }
+
+    #[test]
+    fn recv_reports_a_closed_stream() {
+        match recv(b"") {
+            Err(Error::IoError(e)) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn written_envelopes_are_read_back() {
+        let envelope = Envelope::request(3, Message::Text("hello".to_string()));
+        let mut bytes = Vec::new();
+        envelope.write(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), envelope.encoded_len().unwrap());
+
+        let read = recv(&bytes).unwrap();
+        assert_eq!(read.id, Some(3));
+        assert!(matches!(read.message, Message::Text(text) if text == "hello"));
+    }
 }
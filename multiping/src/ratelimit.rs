@@ -0,0 +1,435 @@
+//! Token bucket rate limiting of incoming messages
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::connection::Connection;
+use crate::error::{Error, Result};
+
+/// How often the buckets of addresses which have stopped sending are forgotten
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A bucket which refills continuously at `rate` tokens per second, holding up to one second's worth
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32) -> TokenBucket {
+        TokenBucket {
+            rate: f64::from(rate),
+            tokens: f64::from(rate),
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+    }
+
+    /// Whether `n` tokens can be taken
+    ///
+    /// Requests larger than the bucket succeed once it is full and leave it in debt,
+    /// so a single large message is delayed rather than rejected forever.
+    fn can_take(&self, n: f64) -> bool {
+        self.tokens >= n.min(self.rate)
+    }
+
+    fn take(&mut self, n: f64) {
+        self.tokens -= n;
+    }
+
+    fn is_full(&self) -> bool {
+        self.tokens >= self.rate
+    }
+}
+
+/// A pair of buckets limiting both the message and byte rate of a single source
+#[derive(Debug, Clone)]
+pub(crate) struct Buckets {
+    /// The limit the buckets were created for
+    limit: RateLimit,
+    messages: TokenBucket,
+    bytes: TokenBucket,
+}
+
+impl Buckets {
+    fn new(limit: RateLimit) -> Buckets {
+        Buckets {
+            limit,
+            messages: TokenBucket::new(limit.messages),
+            bytes: TokenBucket::new(limit.bytes),
+        }
+    }
+
+    /// Refill the buckets and check whether a message of `bytes` bytes fits in both
+    fn allows(&mut self, bytes: usize) -> bool {
+        self.messages.refill();
+        self.bytes.refill();
+        self.messages.can_take(1.0) && self.bytes.can_take(bytes as f64)
+    }
+
+    fn take(&mut self, bytes: usize) {
+        self.messages.take(1.0);
+        self.bytes.take(bytes as f64);
+    }
+
+    fn is_full(&mut self) -> bool {
+        self.messages.refill();
+        self.bytes.refill();
+        self.messages.is_full() && self.bytes.is_full()
+    }
+}
+
+/// The maximum sustained rate of messages and bytes per second
+///
+/// Written as `<messages>:<bytes>`, e.g. `50:65536`. Both must be at least 1, as a limit of
+/// zero would block every message forever.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub messages: u32,
+    pub bytes: u32,
+}
+
+impl FromStr for RateLimit {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<RateLimit> {
+        let mut parts = s.splitn(2, ':');
+        let messages = parts.next().and_then(|m| m.parse().ok());
+        let bytes = parts.next().and_then(|b| b.parse().ok());
+
+        match (messages, bytes) {
+            (Some(0), Some(_)) | (Some(_), Some(0)) => Err(Error::InvalidConfig(format!(
+                "rate limit {} would block every message, both rates must be at least 1",
+                s
+            ))),
+            (Some(messages), Some(bytes)) => Ok(RateLimit { messages, bytes }),
+            _ => Err(Error::InvalidConfig(format!(
+                "expected `<messages>:<bytes>`, got {}",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.messages, self.bytes)
+    }
+}
+
+/// What to do with a message that exceeds a rate limit
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum RateLimitAction {
+    /// Silently drop the message
+    Drop,
+    /// Drop the message and tell the client with a [`crate::Message::Error`]
+    #[default]
+    Warn,
+    /// Disconnect the client
+    Disconnect,
+}
+
+impl FromStr for RateLimitAction {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<RateLimitAction> {
+        match s {
+            "drop" => Ok(RateLimitAction::Drop),
+            "warn" => Ok(RateLimitAction::Warn),
+            "disconnect" => Ok(RateLimitAction::Disconnect),
+            _ => Err(Error::InvalidConfig(format!(
+                "unknown rate limit action {}, expected drop, warn or disconnect",
+                s
+            ))),
+        }
+    }
+}
+
+/// Rate limits applied to incoming messages
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RateLimitConfig {
+    /// The limit for each connection, or `None` for no limit
+    pub per_connection: Option<RateLimit>,
+    /// The limit shared by all connections from the same IP address, or `None` for no limit
+    pub per_ip: Option<RateLimit>,
+    /// What to do with messages which exceed either limit
+    pub action: RateLimitAction,
+}
+
+/// Counters of messages which exceeded a rate limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+pub struct RateLimitStats {
+    /// Messages which were over a limit
+    pub limited: u64,
+    /// Connections disconnected for exceeding a limit
+    pub disconnected: u64,
+}
+
+/// Applies a [`RateLimitConfig`] to incoming messages
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    ips: HashMap<IpAddr, Buckets>,
+    /// When the buckets of idle addresses were last forgotten
+    pruned_at: Instant,
+    stats: RateLimitStats,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> RateLimiter {
+        RateLimiter {
+            config,
+            ips: HashMap::new(),
+            pruned_at: Instant::now(),
+            stats: RateLimitStats::default(),
+        }
+    }
+
+    /// Switch to a new config, keeping the counters
+    ///
+    /// Buckets are replaced the next time they're used if their limit changed.
+    pub fn reconfigure(&mut self, config: RateLimitConfig) {
+        self.config = config;
+        self.ips.clear();
+    }
+
+    /// The action to take for messages over the limit
+    pub fn action(&self) -> RateLimitAction {
+        self.config.action
+    }
+
+    /// The counters of limited messages so far
+    pub fn stats(&self) -> RateLimitStats {
+        self.stats
+    }
+
+    /// Check whether `conn` may send a message of `bytes` bytes, consuming from its buckets if so
+    pub fn check(&mut self, conn: &mut Connection, bytes: usize) -> bool {
+        let conn_allows = match self.config.per_connection {
+            Some(limit) => {
+                let buckets = conn.rate_limit();
+                if buckets
+                    .as_ref()
+                    .is_some_and(|buckets| buckets.limit != limit)
+                {
+                    *buckets = None;
+                }
+                buckets
+                    .get_or_insert_with(|| Buckets::new(limit))
+                    .allows(bytes)
+            }
+            None => true,
+        };
+
+        // Unix socket clients have no address to share a limit
+        let ip = conn.peer().ip();
+        let ip_allows = match (self.config.per_ip, ip) {
+            (Some(limit), Some(ip)) => self
+                .ips
+                .entry(ip)
+                .or_insert_with(|| Buckets::new(limit))
+                .allows(bytes),
+            _ => true,
+        };
+
+        if !(conn_allows && ip_allows) {
+            debug!(
+                "connection {} ({}) is over its rate limit",
+                conn.id(),
+                conn.peer()
+            );
+            self.stats.limited += 1;
+            if self.config.action == RateLimitAction::Disconnect {
+                self.stats.disconnected += 1;
+            }
+            return false;
+        }
+
+        if let Some(buckets) = conn.rate_limit() {
+            buckets.take(bytes);
+        }
+        if let Some(buckets) = ip.and_then(|ip| self.ips.get_mut(&ip)) {
+            buckets.take(bytes);
+        }
+
+        self.prune();
+
+        true
+    }
+
+    /// Forget the buckets of idle addresses, at most once every [`PRUNE_INTERVAL`]
+    ///
+    /// Full buckets are equivalent to fresh ones, so nothing is lost by dropping them.
+    fn prune(&mut self) {
+        if self.pruned_at.elapsed() < PRUNE_INTERVAL {
+            return;
+        }
+
+        self.ips.retain(|_, buckets| !buckets.is_full());
+        self.pruned_at = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rate_limit() {
+        assert_eq!(
+            "50:65536".parse::<RateLimit>().unwrap(),
+            RateLimit {
+                messages: 50,
+                bytes: 65536
+            }
+        );
+        assert_eq!("1:1".parse::<RateLimit>().unwrap().to_string(), "1:1");
+    }
+
+    #[test]
+    fn parse_rate_limit_rejects_zero_rates() {
+        for s in &["0:0", "0:65536", "50:0"] {
+            match s.parse::<RateLimit>() {
+                Err(Error::InvalidConfig(e)) => assert!(e.contains("at least 1"), "{}", e),
+                other => panic!("parsed {:?} as {:?}", s, other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn parse_rate_limit_rejects_malformed_limits() {
+        for s in &[
+            "",
+            "50",
+            "50:",
+            ":65536",
+            "50:65536:1",
+            "-1:10",
+            "ten:10",
+            "5000000000:1",
+        ] {
+            assert!(s.parse::<RateLimit>().is_err(), "parsed {:?}", s);
+        }
+    }
+
+    #[test]
+    fn rate_limit_round_trips_through_display() {
+        let limit = RateLimit {
+            messages: 20,
+            bytes: 4096,
+        };
+        assert_eq!(limit.to_string().parse::<RateLimit>().unwrap(), limit);
+    }
+
+    #[test]
+    fn parse_rate_limit_action() {
+        assert_eq!(
+            "drop".parse::<RateLimitAction>().unwrap(),
+            RateLimitAction::Drop
+        );
+        assert_eq!(
+            "warn".parse::<RateLimitAction>().unwrap(),
+            RateLimitAction::Warn
+        );
+        assert_eq!(
+            "disconnect".parse::<RateLimitAction>().unwrap(),
+            RateLimitAction::Disconnect
+        );
+        assert!("Drop".parse::<RateLimitAction>().is_err());
+        assert_eq!(RateLimitAction::default(), RateLimitAction::Warn);
+    }
+
+    #[test]
+    fn bucket_starts_full_and_empties() {
+        let mut bucket = TokenBucket::new(2);
+        assert!(bucket.is_full());
+
+        for _ in 0..2 {
+            bucket.refill();
+            assert!(bucket.can_take(1.0));
+            bucket.take(1.0);
+        }
+
+        bucket.refill();
+        assert!(!bucket.can_take(1.0));
+        assert!(!bucket.is_full());
+    }
+
+    #[test]
+    fn oversized_request_fits_a_full_bucket_and_leaves_it_in_debt() {
+        let mut bucket = TokenBucket::new(10);
+        assert!(bucket.can_take(100.0));
+        bucket.take(100.0);
+
+        bucket.refill();
+        assert!(!bucket.can_take(1.0));
+    }
+
+    #[test]
+    fn buckets_limit_messages_and_bytes_separately() {
+        let mut buckets = Buckets::new(RateLimit {
+            messages: 5,
+            bytes: 100,
+        });
+
+        // the byte bucket runs out first
+        assert!(buckets.allows(60));
+        buckets.take(60);
+        assert!(!buckets.allows(60));
+        assert!(buckets.allows(30));
+        buckets.take(30);
+
+        // then the message bucket, however small the messages
+        let mut buckets = Buckets::new(RateLimit {
+            messages: 2,
+            bytes: 100,
+        });
+        for _ in 0..2 {
+            assert!(buckets.allows(1));
+            buckets.take(1);
+        }
+        assert!(!buckets.allows(1));
+        assert!(!buckets.is_full());
+    }
+
+    #[test]
+    fn idle_addresses_are_pruned_on_an_interval() {
+        let limit = RateLimit {
+            messages: 2,
+            bytes: 100,
+        };
+        let mut limiter = RateLimiter::new(RateLimitConfig {
+            per_ip: Some(limit),
+            ..RateLimitConfig::default()
+        });
+
+        let idle: IpAddr = "192.0.2.1".parse().unwrap();
+        let busy: IpAddr = "192.0.2.2".parse().unwrap();
+        limiter.ips.insert(idle, Buckets::new(limit));
+        let mut buckets = Buckets::new(limit);
+        buckets.take(2);
+        limiter.ips.insert(busy, buckets);
+
+        // too soon since the last prune
+        limiter.prune();
+        assert_eq!(limiter.ips.len(), 2);
+
+        limiter.pruned_at = Instant::now() - PRUNE_INTERVAL;
+        limiter.prune();
+        assert!(!limiter.ips.contains_key(&idle));
+        assert!(limiter.ips.contains_key(&busy));
+    }
+}
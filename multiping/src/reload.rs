@@ -0,0 +1,109 @@
+//! Reloading a running server's config without dropping its connections
+
+use std::fmt;
+use std::sync::mpsc::Sender;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::server::{Input, ServerConfig};
+
+/// A function producing the config to reload
+type Reload = Box<dyn FnMut() -> Result<ReloadedConfig> + Send>;
+
+/// Produces the config a server should switch to when asked to reload, e.g. by re-reading
+/// its config file
+pub(crate) struct Reloader(Reload);
+
+impl Reloader {
+    pub(crate) fn new<F, R>(mut reloader: F) -> Reloader
+    where
+        F: FnMut() -> Result<R> + Send + 'static,
+        R: Into<ReloadedConfig>,
+    {
+        Reloader(Box::new(move || reloader().map(Into::into)))
+    }
+
+    pub(crate) fn reload(&mut self) -> Result<ReloadedConfig> {
+        (self.0)()
+    }
+}
+
+impl fmt::Debug for Reloader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Reloader")
+    }
+}
+
+/// The config a server switches to when it reloads, along with the changes to settings
+/// outside the [`ServerConfig`] which only the program embedding the server knows about
+#[derive(Debug, Clone, Default)]
+pub struct ReloadedConfig {
+    pub config: ServerConfig,
+    /// The settings outside the config which changed but keep their old value until the
+    /// server restarts, such as the addresses it listens on
+    pub restart_required: Vec<String>,
+}
+
+impl From<ServerConfig> for ReloadedConfig {
+    fn from(config: ServerConfig) -> ReloadedConfig {
+        ReloadedConfig {
+            config,
+            restart_required: Vec::new(),
+        }
+    }
+}
+
+/// The outcome of a reload, naming settings by their [`ServerConfig`] field, or as the
+/// reloader named them in [`ReloadedConfig::restart_required`]
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct ReloadReport {
+    /// The settings which changed and are now in effect
+    pub applied: Vec<String>,
+    /// The settings which changed but keep their old value until the server restarts
+    pub restart_required: Vec<String>,
+}
+
+impl ReloadReport {
+    /// Record a changed setting
+    pub(crate) fn changed(&mut self, setting: &str, live: bool) {
+        if live {
+            self.applied.push(setting.to_string());
+        } else {
+            self.restart_required.push(setting.to_string());
+        }
+    }
+}
+
+impl fmt::Display for ReloadReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let list = |settings: &[String]| {
+            if settings.is_empty() {
+                "nothing".to_string()
+            } else {
+                settings.join(", ")
+            }
+        };
+        write!(
+            f,
+            "applied {}, restart required for {}",
+            list(&self.applied),
+            list(&self.restart_required)
+        )
+    }
+}
+
+/// Asks a running server to reload its config, from any thread
+///
+/// Retrieved with [`crate::Server::reload_handle`].
+#[derive(Debug, Clone)]
+pub struct ReloadHandle {
+    pub(crate) input: Sender<Input>,
+}
+
+impl ReloadHandle {
+    /// Ask the server to reload its config once it has handled the messages already queued
+    pub fn reload(&self) -> Result<()> {
+        self.input.send(Input::Reload).map_err(|_| Error::SendError)
+    }
+}
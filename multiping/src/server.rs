@@ -1,58 +1,385 @@
 //! Core server stuff
 
-use std::net::TcpListener;
-use std::sync::mpsc::channel;
+use std::collections::HashSet;
+use std::io;
+use std::net::Shutdown;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
 use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-use crate::connection::ConnectionRegistry;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tracing::{field, Span};
+
+use crate::admin::{AdminReply, AdminRequest, LogFilterSetter};
+use crate::admission::AdmissionConfig;
+use crate::auth::{Principal, TokenStore};
+use crate::ban::{Ban, BanList, BanTarget};
+use crate::command::{Caller, Commands};
+use crate::connection::{ConnectionId, ConnectionRegistry};
+use crate::delivery::{Unacked, DEFAULT_MAX_UNACKED};
 use crate::error::{Error, Result};
-use crate::message::Message;
+use crate::event::{ServerEvent, Subscribers};
+use crate::handle::ServerHandle;
+use crate::history::{History, HistoryConfig};
+use crate::journal::{Journal, JournalConfig};
+use crate::listener::{self, ListenAddr, Listener, ListenerConfig, Stream};
+use crate::mailbox::{MailboxConfig, Mailboxes};
+use crate::message::{
+    self, DisconnectReason, Envelope, ErrorPayload, Message, MessageId, DEFAULT_MAX_MESSAGE_SIZE,
+};
+use crate::ratelimit::{RateLimitAction, RateLimitConfig, RateLimitStats, RateLimiter};
+use crate::reload::{ReloadHandle, ReloadReport, ReloadedConfig, Reloader};
+use crate::stats::{ServerStats, SharedStats, StatsHandle};
+
+/// The principal given to connections when authentication is disabled
+const ANONYMOUS: &str = "anonymous";
+
+/// The default number of bad messages a client may send before it is disconnected
+pub const DEFAULT_MAX_OFFENCES: u32 = 3;
+
+/// The default time between pings measuring each connection's round trip time
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long to spend telling a refused connection why before closing it
+const REFUSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often connections are checked for timeouts, when a listener has them
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Settings for a [`Server`]
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// The tokens clients must authenticate with before joining, or `None` to admit everyone
+    pub tokens: Option<TokenStore>,
+
+    /// The rate limits applied to incoming messages
+    pub rate_limits: RateLimitConfig,
+
+    /// The limits on which connections are accepted
+    pub admission: AdmissionConfig,
+
+    /// The size in bytes of the largest message a client may send
+    pub max_message_size: usize,
+
+    /// The number of malformed or unexpected messages a client may send before it is disconnected
+    pub max_offences: u32,
+
+    /// How many routed messages to retain for clients which join late
+    pub history: HistoryConfig,
+
+    /// Where to durably log every routed message, or `None` to keep nothing on disk
+    pub journal: Option<JournalConfig>,
+
+    /// How many direct messages to hold for each principal while they're offline
+    pub mailbox: MailboxConfig,
+
+    /// The number of messages sent with [`Envelope::ack`] to track for each principal
+    /// until they acknowledge them
+    pub max_unacked: usize,
+
+    /// How often to ping connections to measure their round trip time, or `None` to never
+    pub ping_interval: Option<Duration>,
+
+    /// The principals which may request the server's statistics, or `None` to allow
+    /// every authenticated client
+    pub stats_access: Option<HashSet<Principal>>,
+
+    /// The principals which may administer the server, e.g. by reloading its config, once
+    /// they have presented one of the [`ServerConfig::admin_tokens`]
+    pub admins: HashSet<Principal>,
+
+    /// The tokens admins present with [`Message::AdminAuth`] to be granted admin rights,
+    /// separately from the token they authenticate with, or `None` to grant no connection
+    /// admin rights
+    pub admin_tokens: Option<TokenStore>,
+
+    /// The addresses and principals kept off the server
+    pub bans: BanList,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            tokens: None,
+            rate_limits: RateLimitConfig::default(),
+            admission: AdmissionConfig::default(),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            max_offences: DEFAULT_MAX_OFFENCES,
+            history: HistoryConfig::default(),
+            journal: None,
+            mailbox: MailboxConfig::default(),
+            max_unacked: DEFAULT_MAX_UNACKED,
+            ping_interval: Some(DEFAULT_PING_INTERVAL),
+            stats_access: None,
+            admins: HashSet::new(),
+            admin_tokens: None,
+            bans: BanList::new(),
+        }
+    }
+}
+
+/// Something for the server's main loop to handle
+#[derive(Debug)]
+pub enum Input {
+    /// A message received by a connection, or the reason it failed to receive one
+    Received(ConnectionId, Result<Envelope>),
+    /// A request from a [`ReloadHandle`] to reload the config
+    Reload,
+    /// A message from the application embedding the server, to send to every connection
+    Inject(Message),
+    /// A request to stop handling input
+    Shutdown,
+}
 
 /// The multiping server
 #[derive(Debug)]
 pub struct Server {
-    listener: Option<JoinHandle<Result<()>>>,
+    /// The listener threads, by the address they're bound to
+    listeners: Vec<(ListenAddr, JoinHandle<Result<()>>)>,
+    /// Tells the listener threads to stop once they're woken
+    stopping: Arc<AtomicBool>,
     connections: Arc<Mutex<ConnectionRegistry>>,
+    /// The admission limits, shared with the listener thread so they can be reloaded
+    admission: Arc<Mutex<AdmissionConfig>>,
+    /// The bans, shared with the listener thread so it can refuse banned addresses
+    bans: Arc<Mutex<BanList>>,
+    /// The principals muted by an admin
+    muted: HashSet<Principal>,
+    /// Sends input to the main loop, from connections and [`ReloadHandle`]s
+    input_tx: Sender<Input>,
+    input_rx: Receiver<Input>,
+    reloader: Option<Reloader>,
+    log_filter: Option<LogFilterSetter>,
+    /// When to next ping the connections, if they're pinged at all
+    next_ping: Option<Instant>,
+    /// When to next check the connections for timeouts, if any listener has them
+    next_expire: Option<Instant>,
+    limiter: RateLimiter,
+    commands: Commands,
+    history: History,
+    journal: Option<Journal>,
+    mailboxes: Mailboxes,
+    unacked: Unacked,
+    stats: SharedStats,
+    events: Subscribers,
+    /// The id to give the next message the server routes
+    next_message_id: MessageId,
+    config: ServerConfig,
 }
 
 impl Server {
-    /// Create a server and bind it to the given address
+    /// Create a server with the default config
     pub fn new() -> Server {
+        Server::with_config(ServerConfig::default())
+    }
+
+    /// Create a server with the given config
+    pub fn with_config(config: ServerConfig) -> Server {
         debug!("create server");
 
+        let connections = ConnectionRegistry::new();
+        let (input_tx, input_rx) = channel();
+
         Server {
-            listener: None,
-            connections: Arc::new(Mutex::new(ConnectionRegistry::new())),
+            listeners: Vec::new(),
+            stopping: Arc::new(AtomicBool::new(false)),
+            stats: connections.stats().clone(),
+            events: connections.events().clone(),
+            connections: Arc::new(Mutex::new(connections)),
+            admission: Arc::new(Mutex::new(config.admission.clone())),
+            bans: Arc::new(Mutex::new(config.bans.clone())),
+            muted: HashSet::new(),
+            input_tx,
+            input_rx,
+            reloader: None,
+            log_filter: None,
+            next_ping: None,
+            next_expire: None,
+            limiter: RateLimiter::new(config.rate_limits.clone()),
+            commands: Commands::new(),
+            history: History::new(config.history.clone()),
+            journal: None,
+            mailboxes: Mailboxes::new(config.mailbox.clone()),
+            unacked: Unacked::new(config.max_unacked),
+            next_message_id: 0,
+            config,
         }
     }
 
-    pub fn connections(&mut self) -> MutexGuard<ConnectionRegistry> {
+    pub fn connections(&mut self) -> MutexGuard<'_, ConnectionRegistry> {
         debug!("acquire lock on client registry");
         self.connections.lock().expect("mutex poisoned")
     }
 
-    /// Spawn a new thread to listen for connections
+    /// Register a command which clients can invoke with a [`Message::Command`]
+    ///
+    /// See [`Commands::register`].
+    pub fn register_command<A, R, F>(&mut self, name: &str, handler: F)
+    where
+        A: DeserializeOwned,
+        R: Serialize,
+        F: Fn(&Caller, A) -> Result<R> + Send + 'static,
+    {
+        self.commands.register(name, handler);
+    }
+
+    /// Receive every [`ServerEvent`] from now on
+    ///
+    /// The channel is unbounded, so drop the receiver once it's no longer read.
+    pub fn subscribe(&self) -> Receiver<ServerEvent> {
+        self.events.subscribe()
+    }
+
+    /// Set where the config comes from when the server is asked to reload it, with a
+    /// [`ReloadHandle`] or by an admin sending [`Message::Reload`]
+    ///
+    /// Changes to the tokens, rate limits, admission limits, offence limit, ping interval,
+    /// stats access and admins are applied straight away. Anything else keeps its old value
+    /// until the server restarts, as does turning authentication on or off. The bans are
+    /// only replaced by a list saved to a file, so a reload without one keeps the bans
+    /// admins added while the server ran.
+    ///
+    /// The reloader can return a [`ReloadedConfig`] instead of a bare [`ServerConfig`], to
+    /// add settings of its own which need a restart to the [`ReloadReport`].
+    pub fn on_reload<F, R>(&mut self, reloader: F)
+    where
+        F: FnMut() -> Result<R> + Send + 'static,
+        R: Into<ReloadedConfig>,
+    {
+        self.reloader = Some(Reloader::new(reloader));
+    }
+
+    /// Set how the log filter is changed when an admin sends [`AdminRequest::SetLogFilter`]
+    ///
+    /// The logger belongs to the program embedding the server, so without this the log
+    /// filter can't be changed.
+    pub fn on_log_filter<F>(&mut self, setter: F)
+    where
+        F: FnMut(&str) -> Result<()> + Send + 'static,
+    {
+        self.log_filter = Some(LogFilterSetter::new(setter));
+    }
+
+    /// Retrieve a handle for asking the server to reload its config while it runs
+    pub fn reload_handle(&self) -> ReloadHandle {
+        ReloadHandle {
+            input: self.input_tx.clone(),
+        }
+    }
+
+    /// Retrieve the counters of rate limited messages
+    pub fn rate_limit_stats(&self) -> RateLimitStats {
+        self.limiter.stats()
+    }
+
+    /// Take a snapshot of the server's statistics
+    pub fn stats(&self) -> ServerStats {
+        self.stats_handle().stats()
+    }
+
+    /// Retrieve a handle for reading the server's statistics while it runs
+    pub fn stats_handle(&self) -> StatsHandle {
+        StatsHandle {
+            connections: self.connections.clone(),
+            stats: self.stats.clone(),
+        }
+    }
+
+    /// Listen for connections on `addr` and handle them until the server stops
+    ///
+    /// Shorthand for [`Server::listen`] followed by [`Server::serve`].
     pub fn run(&mut self, addr: &str) -> Result<()> {
-        debug!("Running server on {}...", addr);
+        self.listen(ListenerConfig::new(ListenAddr::Tcp(addr.to_string())))?;
+        self.serve()
+    }
 
-        println!("Server running on {}", addr);
+    /// Spawn a new thread to accept connections as `config` describes, returning the address
+    /// it is bound to
+    ///
+    /// Connections are accepted straight away, and their messages are handled once the
+    /// server is [served](Server::serve). Any number of listeners can feed one server.
+    pub fn listen(&mut self, config: ListenerConfig) -> Result<ListenAddr> {
+        debug!("bind listener on {}", config.addr);
 
-        let (msg_tx, msg_rx) = channel();
+        let listener = Listener::bind(&config.addr)?;
+        let local_addr = listener.local_addr()?;
+        info!("listening on {}", local_addr);
 
-        let listener = TcpListener::bind(addr)?;
         let conns = self.connections.clone();
+        let require_auth = config.require_auth && self.config.tokens.is_some();
+        let timeouts = config.timeouts;
+        if timeouts.is_enabled() {
+            self.next_expire = Some(Instant::now() + EXPIRE_INTERVAL);
+        }
+        let admission = self.admission.clone();
+        let bans = self.bans.clone();
+        let msg_tx = self.input_tx.clone();
+        let max_message_size = self.config.max_message_size;
+        let stats = self.stats.clone();
+        let stopping = self.stopping.clone();
+        let span = info_span!("listener", addr = %local_addr);
 
         // spawn listener thread
-        self.listener = Some(thread::spawn(move || {
+        let handle = thread::spawn(move || {
+            let _span = span.entered();
+
             // Spawn a thread for each incoming connection
-            for stream in listener.incoming() {
+            loop {
+                let stream = listener.accept();
+                if stopping.load(Ordering::SeqCst) {
+                    debug!("stop listening");
+                    break;
+                }
                 info!("new incoming connection");
                 match stream {
                     Ok(s) => {
+                        let mut conns = conns.lock().expect("mutex poisoned");
+
+                        // Turn away connections which are over the limits
+                        let admitted = s.peer().map_err(Error::from).and_then(|peer| {
+                            let bans = bans.lock().expect("mutex poisoned");
+                            if peer.ip().is_some_and(|ip| bans.is_address_banned(ip)) {
+                                return Err(Error::Banned);
+                            }
+                            let admission = admission.lock().expect("mutex poisoned");
+                            admission.admit(&peer, &conns)
+                        });
+                        if let Err(e) = admitted {
+                            warn!("refusing connection: {}", e);
+                            stats.lock().expect("mutex poisoned").connections_rejected += 1;
+                            // refusing blocks on the client, which mustn't hold up the registry
+                            drop(conns);
+                            refuse(s, e);
+                            continue;
+                        }
+
                         // Add the client to the registry
                         debug!("lock client registry and register new connection");
-                        conns.lock().expect("mutex poisoned").add(s, msg_tx.clone());
+                        let id = match conns.add(s, msg_tx.clone(), max_message_size) {
+                            Ok(id) => id,
+                            Err(e) => {
+                                warn!("failed to register connection: {}", e);
+                                continue;
+                            }
+                        };
+                        stats.lock().expect("mutex poisoned").connections_accepted += 1;
+                        if let Ok(conn) = conns.get_mut(id) {
+                            conn.set_timeouts(timeouts);
+                        }
+
+                        // without tokens every connection may join straight away
+                        if !require_auth {
+                            if let Ok(conn) = conns.get_mut(id) {
+                                conn.authenticate(ANONYMOUS.to_string());
+                                conns.events().publish(ServerEvent::Authenticated {
+                                    id,
+                                    principal: ANONYMOUS.to_string(),
+                                });
+                            }
+                        }
                     }
                     Err(e) => {
                         warn!("failed to accept connection: {}", e);
@@ -61,40 +388,1048 @@ impl Server {
             }
 
             Ok(())
-        }));
+        });
+        self.listeners.push((local_addr.clone(), handle));
+
+        Ok(local_addr)
+    }
+
+    /// Handle the connections from every listener, blocking until the server stops
+    pub fn serve(&mut self) -> Result<()> {
+        debug!("serve connections");
+
+        self.open_journal()?;
+        self.main_loop();
+
+        Ok(())
+    }
+
+    /// Handle the connections from every listener on a new thread, returning a handle which
+    /// controls the server
+    ///
+    /// Listeners should be added with [`Server::listen`] beforehand.
+    pub fn start(mut self) -> Result<ServerHandle> {
+        debug!("start server");
+
+        // open the journal now so that the caller hears if it fails
+        self.open_journal()?;
+
+        let local_addrs = self
+            .listeners
+            .iter()
+            .map(|(addr, _)| addr.clone())
+            .collect();
+        let input = self.input_tx.clone();
+        let connections = self.connections.clone();
+        let stats = self.stats_handle();
+        let events = self.events.clone();
+        let thread = thread::spawn(move || self.main_loop());
+
+        Ok(ServerHandle {
+            local_addrs,
+            input,
+            connections,
+            stats,
+            events,
+            thread: Some(thread),
+        })
+    }
+
+    /// Handle input until the server is shut down
+    fn main_loop(&mut self) {
+        self.schedule_ping();
 
         loop {
             // Read messages received from all connections
             debug!("wait for queued message from client handlers");
 
-            match msg_rx.recv() {
-                Ok((id, msg)) => {
-                    debug!("received message from client {}: {}", id, msg);
+            let next_timer = match (self.next_ping, self.next_expire) {
+                (Some(ping), Some(expire)) => Some(ping.min(expire)),
+                (ping, expire) => ping.or(expire),
+            };
+            let received = match next_timer {
+                Some(at) => self
+                    .input_rx
+                    .recv_timeout(at.saturating_duration_since(Instant::now())),
+                None => self.input_rx.recv().map_err(RecvTimeoutError::from),
+            };
 
-                    match msg {
-                        Message::Ping | Message::Text(_) => {
-                            // distribute the message to the other clients
-                            if let Err(e) = self.connections().forward_to_all(msg, id) {
-                                error!("failed to forward message to all connections: {}", e);
-                            }
-                        }
-                        Message::Disconnect => {
-                            // disconnect the connection that produced the message
-                            self.connections().disconnect(id)?;
-                        }
-                        _ => return Err(Error::UnexpectedMessage(msg)),
+            match received {
+                Ok(Input::Received(id, received)) => {
+                    let started = Instant::now();
+                    self.stats.lock().expect("mutex poisoned").dequeued();
+
+                    self.dispatch(id, received);
+
+                    self.stats
+                        .lock()
+                        .expect("mutex poisoned")
+                        .dispatch_seconds
+                        .observe(started.elapsed().as_secs_f64());
+                }
+                Ok(Input::Reload) => {
+                    if let Err(e) = self.reload() {
+                        error!("{}", e);
                     }
                 }
+                Ok(Input::Inject(message)) => {
+                    if let Err(e) = self.route(None, message, false) {
+                        error!("failed to inject message: {}", e);
+                    }
+                }
+                Ok(Input::Shutdown) => {
+                    info!("shut down");
+                    break;
+                }
+                Err(RecvTimeoutError::Timeout) => {}
                 Err(e) => {
                     // failed to `recv` a message, all senders are dead
                     error!("error whilst receiving message: {}", e);
                     break;
                 }
             }
+
+            // a busy server may never time out waiting, so check the timers every time
+            self.run_timers();
+        }
+    }
+
+    /// Ping the connections and check them for timeouts, if it's time to
+    fn run_timers(&mut self) {
+        let now = Instant::now();
+
+        if self.next_ping.is_some_and(|at| at <= now) {
+            self.ping_all();
+            self.schedule_ping();
+        }
+
+        if self.next_expire.is_some_and(|at| at <= now) {
+            if let Err(e) = self.connections().expire(now) {
+                error!("failed to expire connections: {}", e);
+            }
+            self.next_expire = Some(now + EXPIRE_INTERVAL);
+        }
+    }
+
+    /// Handle a message, or the failure to receive one, from a connection
+    fn dispatch(&mut self, id: ConnectionId, received: Result<Envelope>) {
+        let span = match self.connections().get(id) {
+            Ok(conn) => conn.span().clone(),
+            Err(_) => Span::none(),
+        };
+        let _span = span.entered();
+
+        let envelope = match received {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                if let Err(e) = self.receive_failed(id, e) {
+                    error!("failed to clean up connection {}: {}", id, e);
+                }
+                return;
+            }
+        };
+
+        let message_span = info_span!("message", message_id = field::Empty);
+        if let Some(message_id) = envelope.id {
+            message_span.record("message_id", message_id);
+        }
+        let _message_span = message_span.entered();
+        debug!("received message from client {}: {}", id, envelope.message);
+
+        let mut conns = self.connections.lock().expect("mutex poisoned");
+        let conn = match conns.get_mut(id) {
+            Ok(conn) => conn,
+            Err(_) => {
+                debug!("ignore message from removed connection {}", id);
+                return;
+            }
+        };
+
+        conn.received(&envelope.message);
+
+        let bytes = envelope.encoded_len().unwrap_or(0);
+        let authenticated = conn.is_authenticated();
+        let within_limit = self.limiter.check(conn, bytes);
+        drop(conns);
+
+        {
+            let mut stats = self.stats.lock().expect("mutex poisoned");
+            stats.record_in(&envelope, bytes);
+            stats.rate_limits = self.limiter.stats();
+        }
+        self.events.publish(ServerEvent::Received {
+            id,
+            envelope: Envelope {
+                message: envelope.message.redacted(),
+                ..envelope.clone()
+            },
+        });
+
+        let request = envelope.id;
+        let reply_to = envelope.reply_to;
+        let ack = envelope.ack;
+
+        if !within_limit {
+            if let Err(e) = self.rate_limited(id, request) {
+                error!("failed to rate limit connection {}: {}", id, e);
+            }
+            return;
+        }
+
+        let handled = match envelope.message {
+            Message::Auth { token } => self.authenticate(id, request, &token),
+            _ if !authenticated => {
+                warn!("connection {} sent a message before authenticating", id);
+                self.reject(
+                    id,
+                    request,
+                    Error::NotAuthenticated,
+                    DisconnectReason::ProtocolError,
+                )
+            }
+            Message::AdminAuth { token } => self.authenticate_admin(id, request, &token),
+            Message::Ping => {
+                // distribute the ping to the other clients and answer it
+                self.route(Some(id), Message::Ping, false)
+                    .and_then(|_| self.connections().reply(id, request, Message::Pong))
+            }
+            Message::Text(_) | Message::Direct { .. } if self.is_muted(id) => {
+                debug!("drop message from muted connection {}", id);
+                self.connections()
+                    .forward(id, Envelope::error(request, &Error::Muted))
+            }
+            Message::Text(text) => {
+                // distribute the message to the other clients
+                self.route(Some(id), Message::Text(text), ack)
+            }
+            Message::Direct { to, text } => self.direct(id, request, to, text, ack),
+            Message::Ack(acked) => self.acknowledge(id, acked),
+            Message::Pong if reply_to.is_some() => self.pong(id, reply_to),
+            Message::Stats => self.report_stats(id, request),
+            Message::Reload => self.reload_request(id, request),
+            Message::Admin(admin) => self.admin_request(id, request, admin),
+            Message::History { since } => self.replay(id, request, since),
+            Message::Command { name, args } => self.command(id, request, &name, args),
+            Message::Disconnect { .. } => {
+                // disconnect the connection that produced the message
+                self.connections()
+                    .disconnect(id, DisconnectReason::ClientRequested)
+            }
+            msg => {
+                warn!("connection {} sent an unexpected message: {}", id, msg);
+                self.offence(id, request, Error::UnexpectedMessage(msg))
+            }
+        };
+
+        if let Err(e) = handled {
+            error!("failed to handle message from connection {}: {}", id, e);
+            self.events.publish(ServerEvent::Error {
+                id,
+                error: ErrorPayload::from(&e),
+            });
+        }
+    }
+
+    /// Set when to next ping the connections, one interval from now
+    fn schedule_ping(&mut self) {
+        self.next_ping = self
+            .config
+            .ping_interval
+            .map(|interval| Instant::now() + interval);
+    }
+
+    /// Ping every authenticated connection to measure its round trip time
+    fn ping_all(&mut self) {
+        self.next_message_id += 1;
+        let ping = self.next_message_id;
+
+        if let Err(e) = self.connections().ping_all(ping) {
+            error!("failed to ping connections: {}", e);
+        }
+    }
+
+    /// Record a connection's reply to a ping from the server
+    fn pong(&mut self, id: ConnectionId, reply_to: Option<MessageId>) -> Result<()> {
+        let mut conns = self.connections.lock().expect("mutex poisoned");
+        let conn = conns.get_mut(id)?;
+
+        match reply_to.and_then(|ping| conn.pong(ping)) {
+            Some(rtt) => {
+                debug!("connection {} round trip time {:?}", id, rtt);
+                self.stats
+                    .lock()
+                    .expect("mutex poisoned")
+                    .rtt_seconds
+                    .observe(rtt.as_secs_f64());
+            }
+            None => debug!("ignore stale pong from connection {}", id),
+        }
+
+        Ok(())
+    }
+
+    /// Run a command for a connection and send it the result
+    fn command(
+        &mut self,
+        id: ConnectionId,
+        request: Option<MessageId>,
+        name: &str,
+        args: serde_json::Value,
+    ) -> Result<()> {
+        let caller = self.caller(id)?;
+        let reply = match self.commands.invoke(&caller, name, args) {
+            Ok(result) => Envelope::reply(request, Message::CommandResult(result)),
+            Err(e) => {
+                warn!("command {} from connection {} failed: {}", name, id, e);
+                Envelope::error(request, &e)
+            }
+        };
+
+        self.connections().forward(id, reply)
+    }
+
+    /// Send a snapshot of the server's statistics to a connection whose principal may see them
+    fn report_stats(&mut self, id: ConnectionId, request: Option<MessageId>) -> Result<()> {
+        let principal = self.connections().get(id)?.principal().cloned();
+
+        let reply = if self.may_see_stats(principal.as_ref()) {
+            Envelope::reply(request, Message::StatsReport(Box::new(self.stats())))
+        } else {
+            warn!("connection {} may not see the server's statistics", id);
+            Envelope::error(request, &Error::NotAuthorised)
+        };
+
+        self.connections().forward(id, reply)
+    }
+
+    /// Whether connections authenticated as `principal` may see the server's statistics
+    fn may_see_stats(&self, principal: Option<&Principal>) -> bool {
+        match (&self.config.stats_access, principal) {
+            (Some(access), Some(principal)) => access.contains(principal),
+            (Some(_), None) => false,
+            (None, _) => true,
+        }
+    }
+
+    /// Describe a connection to the command it invoked
+    fn caller(&self, id: ConnectionId) -> Result<Caller> {
+        let principal = self
+            .connections
+            .lock()
+            .expect("mutex poisoned")
+            .get(id)?
+            .principal()
+            .cloned();
+
+        Ok(Caller {
+            id,
+            admin: self.is_admin(id)?,
+            stats_access: self.may_see_stats(principal.as_ref()),
+            principal,
+        })
+    }
+
+    /// Whether a connection has presented an admin token for a principal which is still one
+    /// of the server's admins and still has an admin token
+    fn is_admin(&self, id: ConnectionId) -> Result<bool> {
+        let conns = self.connections.lock().expect("mutex poisoned");
+        let conn = conns.get(id)?;
+        Ok(conn.is_admin()
+            && conn.principal().is_some_and(|principal| {
+                self.config.admins.contains(principal)
+                    && self
+                        .config
+                        .admin_tokens
+                        .as_ref()
+                        .is_some_and(|tokens| tokens.knows(principal))
+            }))
+    }
+
+    /// Whether a connection has authenticated as a principal an admin muted
+    fn is_muted(&self, id: ConnectionId) -> bool {
+        let conns = self.connections.lock().expect("mutex poisoned");
+        conns
+            .get(id)
+            .ok()
+            .and_then(|conn| conn.principal())
+            .is_some_and(|principal| self.muted.contains(principal))
+    }
+
+    /// Carry out an administrative operation for a connection whose principal is an admin,
+    /// and tell it the outcome
+    fn admin_request(
+        &mut self,
+        id: ConnectionId,
+        request: Option<MessageId>,
+        admin: AdminRequest,
+    ) -> Result<()> {
+        let reply = if self.is_admin(id)? {
+            info!("connection {} asked to {}", id, admin);
+            match self.administer(admin) {
+                Ok(reply) => Envelope::reply(request, Message::AdminReply(reply)),
+                Err(e) => {
+                    warn!("admin request from connection {} failed: {}", id, e);
+                    Envelope::error(request, &e)
+                }
+            }
+        } else {
+            warn!("connection {} may not {}", id, admin);
+            Envelope::error(request, &Error::NotAuthorised)
+        };
+
+        let mut conns = self.connections();
+        if conns.get(id).is_err() {
+            debug!("connection {} disconnected itself", id);
+            return Ok(());
+        }
+        conns.forward(id, reply)
+    }
+
+    /// Carry out an administrative operation
+    fn administer(&mut self, admin: AdminRequest) -> Result<AdminReply> {
+        match admin {
+            AdminRequest::Connections => {
+                return Ok(AdminReply::Connections(self.connections().info()));
+            }
+            AdminRequest::Kick(target) => {
+                self.connections()
+                    .disconnect(target, DisconnectReason::Kicked)?;
+            }
+            AdminRequest::Ban { target, seconds } => {
+                let expires = match seconds {
+                    Some(seconds) => Some(
+                        seconds
+                            .checked_mul(1000)
+                            .and_then(|ms| message::timestamp().checked_add(ms))
+                            .ok_or_else(|| {
+                                Error::InvalidArguments(format!(
+                                    "a ban of {} seconds is too long",
+                                    seconds
+                                ))
+                            })?,
+                    ),
+                    None => None,
+                };
+                self.bans.lock().expect("mutex poisoned").add(Ban {
+                    target: target.clone(),
+                    expires,
+                })?;
+
+                let mut conns = self.connections.lock().expect("mutex poisoned");
+                let banned: Vec<_> = conns
+                    .info()
+                    .into_iter()
+                    .filter(|info| match &target {
+                        BanTarget::Address(range) => {
+                            info.peer.ip().is_some_and(|ip| range.contains(ip))
+                        }
+                        BanTarget::Principal(principal) => {
+                            info.principal.as_ref() == Some(principal)
+                        }
+                    })
+                    .map(|info| info.id)
+                    .collect();
+                // a connection which has already gone mustn't keep the others connected
+                for id in banned {
+                    let disconnected = conns
+                        .forward(id, Envelope::error(None, &Error::Banned))
+                        .and_then(|_| conns.disconnect(id, DisconnectReason::Banned));
+                    if let Err(e) = disconnected {
+                        warn!("failed to disconnect banned connection {}: {}", id, e);
+                    }
+                }
+            }
+            AdminRequest::Unban(target) => {
+                if !self.bans.lock().expect("mutex poisoned").remove(&target)? {
+                    return Err(Error::CommandFailed(format!("{} is not banned", target)));
+                }
+            }
+            AdminRequest::Bans => {
+                let bans = self.bans.lock().expect("mutex poisoned").bans();
+                return Ok(AdminReply::Bans(bans));
+            }
+            AdminRequest::Notice(text) => self.route(None, Message::Notice(text), false)?,
+            AdminRequest::Mute(principal) => {
+                self.muted.insert(principal);
+            }
+            AdminRequest::Unmute(principal) => {
+                self.muted.remove(&principal);
+            }
+            AdminRequest::SetLogFilter(directives) => match self.log_filter.as_mut() {
+                Some(log_filter) => log_filter.set(&directives)?,
+                None => {
+                    return Err(Error::CommandFailed(
+                        "the log filter can't be changed".to_string(),
+                    ))
+                }
+            },
+        }
+
+        Ok(AdminReply::Done)
+    }
+
+    /// Reload the config for a connection whose principal is an admin, and tell it what changed
+    fn reload_request(&mut self, id: ConnectionId, request: Option<MessageId>) -> Result<()> {
+        let reply = if self.is_admin(id)? {
+            match self.reload() {
+                Ok(report) => Envelope::reply(request, Message::Reloaded(report)),
+                Err(e) => {
+                    error!("{}", e);
+                    Envelope::error(request, &e)
+                }
+            }
+        } else {
+            warn!("connection {} may not reload the config", id);
+            Envelope::error(request, &Error::NotAuthorised)
+        };
+
+        self.connections().forward(id, reply)
+    }
+
+    /// Switch to the config produced by the reloader
+    fn reload(&mut self) -> Result<ReloadReport> {
+        let reloader = self
+            .reloader
+            .as_mut()
+            .ok_or_else(|| Error::ReloadFailed("no config source to reload from".to_string()))?;
+        let reloaded = reloader
+            .reload()
+            .map_err(|e| Error::ReloadFailed(e.to_string()))?;
+
+        let mut report = self.reconfigure(reloaded.config);
+        for setting in &reloaded.restart_required {
+            report.changed(setting, false);
+        }
+        info!("reloaded config: {}", report);
+        if !report.restart_required.is_empty() {
+            warn!(
+                "changes to {} take effect after a restart",
+                report.restart_required.join(", ")
+            );
+        }
+
+        Ok(report)
+    }
+
+    /// Apply the settings in `config` which can change while the server runs, reporting
+    /// which settings changed
+    fn reconfigure(&mut self, config: ServerConfig) -> ReloadReport {
+        let mut report = ReloadReport::default();
+
+        if config.tokens != self.config.tokens {
+            // the listener decides whether connections must authenticate when it starts
+            let live = config.tokens.is_some() == self.config.tokens.is_some();
+            report.changed("tokens", live);
+            if live {
+                self.config.tokens = config.tokens;
+                self.disconnect_revoked();
+            }
+        }
+
+        if config.rate_limits != self.config.rate_limits {
+            report.changed("rate_limits", true);
+            self.limiter.reconfigure(config.rate_limits.clone());
+            self.config.rate_limits = config.rate_limits;
+        }
+
+        if config.admission != self.config.admission {
+            report.changed("admission", true);
+            *self.admission.lock().expect("mutex poisoned") = config.admission.clone();
+            self.config.admission = config.admission;
+        }
+
+        // bans added at runtime only survive a reload if they were saved to a file,
+        // so a list without one never replaces the live list
+        let mut bans = self.bans.lock().expect("mutex poisoned");
+        if config.bans.path().is_some()
+            && (config.bans.path() != bans.path() || config.bans.bans() != bans.bans())
+        {
+            report.changed("bans", true);
+            *bans = config.bans.clone();
+            self.config.bans = config.bans;
+        }
+        drop(bans);
+
+        if config.max_offences != self.config.max_offences {
+            report.changed("max_offences", true);
+            self.config.max_offences = config.max_offences;
+        }
+
+        if config.ping_interval != self.config.ping_interval {
+            report.changed("ping_interval", true);
+            self.config.ping_interval = config.ping_interval;
+            self.schedule_ping();
+        }
+
+        if config.stats_access != self.config.stats_access {
+            report.changed("stats_access", true);
+            self.config.stats_access = config.stats_access;
+        }
+
+        if config.admins != self.config.admins {
+            report.changed("admins", true);
+            self.config.admins = config.admins;
+        }
+
+        if config.admin_tokens != self.config.admin_tokens {
+            report.changed("admin_tokens", true);
+            self.config.admin_tokens = config.admin_tokens;
+        }
+
+        // these are baked into connections, the listener or the stored messages
+        let restart_required = [
+            (
+                "max_message_size",
+                config.max_message_size != self.config.max_message_size,
+            ),
+            ("history", config.history != self.config.history),
+            ("journal", config.journal != self.config.journal),
+            ("mailbox", config.mailbox != self.config.mailbox),
+            ("max_unacked", config.max_unacked != self.config.max_unacked),
+        ];
+        for (setting, changed) in &restart_required {
+            if *changed {
+                report.changed(setting, false);
+            }
+        }
+
+        report
+    }
+
+    /// Disconnect the connections authenticated as a principal which no longer has a token
+    ///
+    /// Connections admitted without a token, on a listener which doesn't require one, are
+    /// left alone.
+    fn disconnect_revoked(&mut self) {
+        let tokens = match &self.config.tokens {
+            Some(tokens) => tokens,
+            None => return,
+        };
+
+        let mut conns = self.connections.lock().expect("mutex poisoned");
+        let revoked: Vec<_> = conns
+            .info()
+            .into_iter()
+            .filter(|info| {
+                info.principal
+                    .as_deref()
+                    .is_some_and(|principal| principal != ANONYMOUS && !tokens.knows(principal))
+            })
+            .map(|info| info.id)
+            .collect();
+        // a connection which has already gone mustn't keep the others connected
+        for id in revoked {
+            info!("disconnect connection {}, whose token was removed", id);
+            let disconnected = conns
+                .forward(id, Envelope::error(None, &Error::AuthenticationFailed))
+                .and_then(|_| conns.disconnect(id, DisconnectReason::TokenRevoked));
+            if let Err(e) = disconnected {
+                warn!("failed to disconnect revoked connection {}: {}", id, e);
+            }
+        }
+    }
+
+    /// Open the configured journal and replay the messages already in it into the history,
+    /// so message ids carry on where the previous run left off
+    fn open_journal(&mut self) -> Result<()> {
+        let config = match &self.config.journal {
+            Some(config) => config.clone(),
+            None => return Ok(()),
+        };
+
+        let journal = Journal::open(config.clone())?;
+
+        // the history only keeps the newest messages, so the journal is never all in memory
+        let mut replayed = 0;
+        for envelope in Journal::read(&config.dir)? {
+            let envelope = envelope?;
+            if let Some(id) = envelope.id {
+                self.next_message_id = self.next_message_id.max(id);
+            }
+            self.history.record(envelope);
+            replayed += 1;
+        }
+        info!("replayed {} journaled messages", replayed);
+
+        self.journal = Some(journal);
+
+        Ok(())
+    }
+
+    /// Stamp a message from a connection with its id, sender and timestamp, and log it
+    /// to the journal unless it is a ping
+    fn stamp(&mut self, source: Option<ConnectionId>, msg: Message, ack: bool) -> Result<Envelope> {
+        let sender = match source {
+            Some(source) => self.connections().get(source)?.principal().cloned(),
+            None => None,
+        };
+        self.next_message_id += 1;
+
+        let envelope = Envelope {
+            id: Some(self.next_message_id),
+            sender,
+            timestamp: Some(message::timestamp()),
+            ack,
+            ..Envelope::new(msg)
+        };
+
+        // pings are only meaningful live, so aren't worth keeping
+        let durable = !matches!(envelope.message, Message::Ping);
+        if let Some(journal) = self.journal.as_mut().filter(|_| durable) {
+            if let Err(e) = journal.append(&envelope) {
+                error!(
+                    "failed to write message {} to the journal: {}",
+                    self.next_message_id, e
+                );
+            }
+        }
+
+        Ok(envelope)
+    }
+
+    /// Stamp a message from a connection, or the server itself if `source` is `None`, retain it
+    /// in the history and send it to every other connection
+    ///
+    /// If `ack` is set the message is retransmitted to each recipient until they acknowledge it.
+    fn route(&mut self, source: Option<ConnectionId>, msg: Message, ack: bool) -> Result<()> {
+        let envelope = self.stamp(source, msg, ack)?;
+        self.history.record(envelope.clone());
+
+        let mut conns = self.connections.lock().expect("mutex poisoned");
+        if ack {
+            for principal in conns.principals_except(source) {
+                self.unacked.track(&principal, envelope.clone());
+            }
+        }
+
+        conns.forward_to_all(envelope.clone(), source)?;
+        self.events
+            .publish(ServerEvent::Routed { source, envelope });
+
+        Ok(())
+    }
+
+    /// Send text from a connection to every connection authenticated as `to`, holding it
+    /// in their mailbox if there are none
+    fn direct(
+        &mut self,
+        source: ConnectionId,
+        request: Option<MessageId>,
+        to: Principal,
+        text: String,
+        ack: bool,
+    ) -> Result<()> {
+        let known = match &self.config.tokens {
+            Some(tokens) => tokens.knows(&to),
+            None => to == ANONYMOUS,
+        };
+        if !known {
+            warn!(
+                "connection {} sent a message to unknown recipient {}",
+                source, to
+            );
+            return self.connections().forward(
+                source,
+                Envelope::error(request, &Error::UnknownRecipient(to)),
+            );
+        }
+
+        let envelope = self.stamp(
+            Some(source),
+            Message::Direct {
+                to: to.clone(),
+                text,
+            },
+            ack,
+        )?;
+
+        // messages awaiting acknowledgement are retransmitted when the recipient
+        // reconnects, so they needn't be held in the mailbox as well, unless they
+        // can't be tracked
+        let tracked = ack && self.unacked.track(&to, envelope.clone());
+
+        let delivered = self
+            .connections()
+            .forward_to_principal(envelope.clone(), &to)?;
+        self.events.publish(ServerEvent::Routed {
+            source: Some(source),
+            envelope: envelope.clone(),
+        });
+        if delivered > 0 || tracked {
+            return Ok(());
+        }
+
+        if self.mailboxes.deposit(&to, envelope) {
+            debug!("{} is offline, holding message in their mailbox", to);
+        } else {
+            debug!("{} is offline, dropping message", to);
+        }
+
+        Ok(())
+    }
+
+    /// Record that a connection's principal received the message `acked`
+    fn acknowledge(&mut self, id: ConnectionId, acked: MessageId) -> Result<()> {
+        let principal = match self.connections().get(id)?.principal() {
+            Some(principal) => principal.clone(),
+            None => return Ok(()),
+        };
+
+        if self.unacked.ack(&principal, acked) {
+            debug!("{} acknowledged message {}", principal, acked);
+        } else {
+            debug!("{} acknowledged unknown message {}", principal, acked);
         }
 
         Ok(())
     }
+
+    /// Replay the retained history to a connection, then reply with the number of
+    /// messages replayed
+    fn replay(
+        &mut self,
+        id: ConnectionId,
+        request: Option<MessageId>,
+        since: Option<u64>,
+    ) -> Result<()> {
+        let history = self.history.since(since);
+        debug!("replay {} messages to connection {}", history.len(), id);
+
+        let mut conns = self.connections.lock().expect("mutex poisoned");
+        let count = history.len();
+        for envelope in history {
+            let replayed = conns.forward(
+                id,
+                Envelope {
+                    replay: true,
+                    ..envelope
+                },
+            );
+            // the rest can't reach a connection which has gone either
+            if let Err(e) = replayed {
+                warn!("stop replaying to connection {}: {}", id, e);
+                return Ok(());
+            }
+        }
+
+        conns.reply(id, request, Message::Replayed(count))
+    }
+
+    /// Authenticate a connection with a token, disconnecting it if the token is invalid
+    fn authenticate(
+        &mut self,
+        id: ConnectionId,
+        request: Option<MessageId>,
+        token: &str,
+    ) -> Result<()> {
+        debug!("authenticate connection {}", id);
+
+        let principal = match &self.config.tokens {
+            Some(tokens) => tokens.authenticate(token).cloned(),
+            None => Some(ANONYMOUS.to_string()),
+        };
+
+        match principal {
+            Some(principal)
+                if self
+                    .bans
+                    .lock()
+                    .expect("mutex poisoned")
+                    .is_principal_banned(&principal) =>
+            {
+                warn!("connection {} authenticated as banned {}", id, principal);
+                self.reject(id, request, Error::Banned, DisconnectReason::Banned)
+            }
+            Some(principal) => {
+                info!("connection {} authenticated as {}", id, principal);
+                let held = self.mailboxes.collect(&principal);
+                let unacked = self.unacked.pending(&principal);
+
+                let mut conns = self.connections.lock().expect("mutex poisoned");
+                conns.get_mut(id)?.authenticate(principal.clone());
+                self.events.publish(ServerEvent::Authenticated {
+                    id,
+                    principal: principal.clone(),
+                });
+                conns.reply(id, request, Message::Authenticated(principal))?;
+
+                debug!("deliver {} held messages to connection {}", held.len(), id);
+                for envelope in held {
+                    conns.forward(id, envelope)?;
+                }
+
+                debug!(
+                    "retransmit {} unacknowledged messages to connection {}",
+                    unacked.len(),
+                    id
+                );
+                for envelope in unacked {
+                    conns.forward(id, envelope)?;
+                }
+
+                Ok(())
+            }
+            None => {
+                warn!("connection {} failed to authenticate", id);
+                self.reject(
+                    id,
+                    request,
+                    Error::AuthenticationFailed,
+                    DisconnectReason::AuthenticationFailed,
+                )
+            }
+        }
+    }
+
+    /// Grant an authenticated connection admin rights if `token` is an admin token for its
+    /// principal, disconnecting it if not
+    fn authenticate_admin(
+        &mut self,
+        id: ConnectionId,
+        request: Option<MessageId>,
+        token: &str,
+    ) -> Result<()> {
+        debug!("authenticate connection {} as an admin", id);
+
+        let mut conns = self.connections.lock().expect("mutex poisoned");
+        let conn = conns.get_mut(id)?;
+        let principal = conn.principal().cloned();
+        let admin = self
+            .config
+            .admin_tokens
+            .as_ref()
+            .and_then(|tokens| tokens.authenticate(token))
+            .filter(|admin| principal.as_ref() == Some(*admin))
+            .filter(|admin| self.config.admins.contains(*admin))
+            .cloned();
+
+        match admin {
+            Some(admin) => {
+                info!("connection {} authenticated as admin {}", id, admin);
+                conn.elevate();
+                conns.reply(id, request, Message::AdminAuthenticated(admin))
+            }
+            None => {
+                drop(conns);
+                warn!("connection {} failed to authenticate as an admin", id);
+                self.reject(
+                    id,
+                    request,
+                    Error::AuthenticationFailed,
+                    DisconnectReason::AuthenticationFailed,
+                )
+            }
+        }
+    }
+
+    /// Handle a connection which failed to receive or send a message
+    ///
+    /// Malformed messages count as an offence, anything else means the stream is unusable
+    /// and the connection is disconnected. A write which timed out means the client isn't
+    /// reading its messages, and it is disconnected as a slow consumer.
+    fn receive_failed(&mut self, id: ConnectionId, err: Error) -> Result<()> {
+        let mut conns = self.connections();
+
+        if conns.get(id).is_err() {
+            debug!("connection {} was already removed: {}", id, err);
+            return Ok(());
+        }
+
+        let reason = match &err {
+            Error::JsonError(_) => {
+                warn!("bad message from connection {}: {}", id, err);
+                drop(conns);
+                return self.offence(id, None, err);
+            }
+            Error::MessageTooLarge(_) => {
+                warn!("connection {} sent an oversized message", id);
+                drop(conns);
+                return self.reject(id, None, err, DisconnectReason::ProtocolError);
+            }
+            Error::IoError(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+                ) =>
+            {
+                warn!("connection {} is not reading its messages: {}", id, err);
+                DisconnectReason::SlowConsumer
+            }
+            _ => {
+                info!("connection {} closed: {}", id, err);
+                DisconnectReason::ConnectionLost
+            }
+        };
+
+        conns.disconnect(id, reason)
+    }
+
+    /// Tell a connection why its message was refused, disconnecting it once it has
+    /// sent more than [`ServerConfig::max_offences`] bad messages
+    fn offence(&mut self, id: ConnectionId, request: Option<MessageId>, err: Error) -> Result<()> {
+        let max_offences = self.config.max_offences;
+        let mut conns = self.connections();
+
+        let offences = conns.get_mut(id)?.record_offence();
+        if offences > max_offences {
+            warn!("connection {} sent too many bad messages", id);
+            drop(conns);
+            return self.reject(id, request, err, DisconnectReason::ProtocolError);
+        }
+
+        debug!("connection {} offence {}/{}", id, offences, max_offences);
+        conns.forward(id, Envelope::error(request, &err))
+    }
+
+    /// Apply the configured [`RateLimitAction`] to a connection which exceeded its rate limit
+    fn rate_limited(&mut self, id: ConnectionId, request: Option<MessageId>) -> Result<()> {
+        match self.limiter.action() {
+            RateLimitAction::Drop => {
+                debug!("drop message from connection {}", id);
+                Ok(())
+            }
+            RateLimitAction::Warn => {
+                debug!("warn connection {}", id);
+                self.connections()
+                    .forward(id, Envelope::error(request, &Error::RateLimited))
+            }
+            RateLimitAction::Disconnect => {
+                warn!("disconnect rate limited connection {}", id);
+                self.reject(
+                    id,
+                    request,
+                    Error::RateLimited,
+                    DisconnectReason::RateLimited,
+                )
+            }
+        }
+    }
+
+    /// Tell a connection why it is being rejected and then disconnect it
+    fn reject(
+        &mut self,
+        id: ConnectionId,
+        request: Option<MessageId>,
+        err: Error,
+        reason: DisconnectReason,
+    ) -> Result<()> {
+        debug!("reject connection {}: {}", id, err);
+
+        let mut conns = self.connections();
+        conns.forward(id, Envelope::error(request, &err))?;
+        conns.disconnect(id, reason)
+    }
+}
+
+/// Tell a connection why it was refused and close it
+fn refuse(mut stream: Stream, err: Error) {
+    // don't let a client which never reads hold up the listener
+    let _ = stream.set_write_timeout(Some(REFUSE_TIMEOUT));
+
+    if let Err(e) = Envelope::error(None, &err).write(&mut stream) {
+        debug!("failed to send refusal: {}", e);
+    }
+    let _ = stream.shutdown(Shutdown::Both);
 }
 
 impl Default for Server {
@@ -108,8 +1443,14 @@ impl std::ops::Drop for Server {
     fn drop(&mut self) {
         debug!("drop server");
 
-        // @TODO implement messaging for listener so it can't hang
-        if let Some(listener) = self.listener.take() {
+        self.stopping.store(true, Ordering::SeqCst);
+        for (addr, listener) in self.listeners.drain(..) {
+            // the thread only sees it should stop once it accepts a connection
+            if let Err(e) = listener::wake(&addr) {
+                error!("failed to wake listener on {}: {}", addr, e);
+                continue;
+            }
+
             debug!("join listener thread");
             match listener.join() {
                 Ok(res) => match res {
@@ -118,11 +1459,20 @@ impl std::ops::Drop for Server {
                 },
                 Err(_) => error!("error joining listener"),
             }
+
+            if let ListenAddr::Unix(path) = &addr {
+                if let Err(e) = std::fs::remove_file(path) {
+                    debug!("failed to remove socket {}: {}", path.display(), e);
+                }
+            }
         }
 
         debug!("disconnect all connections");
 
-        match self.connections().disconnect_all() {
+        match self
+            .connections()
+            .disconnect_all(DisconnectReason::ServerShutdown)
+        {
             Ok(_) => debug!("all connections disconnected successfully"),
             Err(e) => error!("error disconnecting all clients: {}", e),
         }
@@ -130,4 +1480,359 @@ impl std::ops::Drop for Server {
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use std::io::{BufReader, Write};
+    use std::net::TcpStream;
+
+    use super::*;
+    use crate::error::ErrorCode;
+
+    const MAX_SIZE: usize = 64;
+
+    /// Listen on an unused port and start `server`
+    fn start(mut server: Server) -> ServerHandle {
+        server
+            .listen(ListenerConfig::new(ListenAddr::Tcp("127.0.0.1:0".into())))
+            .unwrap();
+        server.start().unwrap()
+    }
+
+    /// A connection to a running server
+    struct TestClient {
+        stream: TcpStream,
+        reader: BufReader<TcpStream>,
+    }
+
+    impl TestClient {
+        fn connect(handle: &ServerHandle) -> TestClient {
+            let stream = TcpStream::connect(handle.local_addrs()[0].to_string()).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let reader = BufReader::new(stream.try_clone().unwrap());
+            TestClient { stream, reader }
+        }
+
+        fn send(&mut self, id: MessageId, message: Message) {
+            Envelope::request(id, message)
+                .write(&mut self.stream)
+                .unwrap();
+        }
+
+        fn recv(&mut self) -> Result<Envelope> {
+            Envelope::recv(&mut self.reader, DEFAULT_MAX_MESSAGE_SIZE)
+        }
+
+        /// Send a request and wait for the reply to it
+        fn request(&mut self, id: MessageId, message: Message) -> Message {
+            self.send(id, message);
+            loop {
+                let envelope = self.recv().unwrap();
+                if envelope.reply_to == Some(id) {
+                    return envelope.message;
+                }
+            }
+        }
+
+        /// Wait for the server to close the connection
+        fn closed(&mut self) {
+            while self.recv().is_ok() {}
+        }
+    }
+
+    fn command_result(message: Message) -> serde_json::Value {
+        match message {
+            Message::CommandResult(result) => result,
+            msg => panic!("unexpected message {}", msg),
+        }
+    }
+
+    fn auth(token: &str) -> Message {
+        Message::Auth {
+            token: token.to_string(),
+        }
+    }
+
+    #[test]
+    fn oversized_messages_are_refused_with_a_typed_error() {
+        let handle = start(Server::with_config(ServerConfig {
+            max_message_size: MAX_SIZE,
+            ..ServerConfig::default()
+        }));
+
+        let mut client = TestClient::connect(&handle);
+        let mut line = vec![b'x'; MAX_SIZE * 2];
+        line.push(b'\n');
+        client.stream.write_all(&line).unwrap();
+
+        let error = loop {
+            if let Message::Error(error) = client.recv().unwrap().message {
+                break error;
+            }
+        };
+        assert_eq!(error.code, ErrorCode::MessageTooLarge);
+
+        // the connection is closed after the error
+        client.closed();
+        assert_eq!(
+            handle.stats().disconnects[&DisconnectReason::ProtocolError],
+            1
+        );
+
+        handle.shutdown().unwrap();
+    }
+
+    #[test]
+    fn commands_are_told_who_invoked_them() {
+        let mut tokens = TokenStore::new();
+        tokens.insert("alice", "alice-token");
+        tokens.insert("bob", "bob-token");
+        let mut server = Server::with_config(ServerConfig {
+            tokens: Some(tokens),
+            stats_access: Some(vec!["alice".to_string()].into_iter().collect()),
+            ..ServerConfig::default()
+        });
+        server.register_command("whoami", |caller: &Caller, ()| {
+            Ok((caller.principal.clone(), caller.stats_access))
+        });
+        let handle = start(server);
+
+        let mut alice = TestClient::connect(&handle);
+        alice.request(1, auth("alice-token"));
+        let mut bob = TestClient::connect(&handle);
+        bob.request(1, auth("bob-token"));
+
+        let whoami = Message::Command {
+            name: "whoami".to_string(),
+            args: serde_json::Value::Null,
+        };
+        assert_eq!(
+            command_result(alice.request(2, whoami.clone())),
+            serde_json::json!(["alice", true])
+        );
+        assert_eq!(
+            command_result(bob.request(2, whoami)),
+            serde_json::json!(["bob", false])
+        );
+
+        handle.shutdown().unwrap();
+    }
+
+    #[test]
+    fn pings_are_not_journaled() {
+        let dir = tempfile::tempdir().unwrap();
+        let handle = start(Server::with_config(ServerConfig {
+            journal: Some(JournalConfig::new(dir.path())),
+            ..ServerConfig::default()
+        }));
+
+        let mut client = TestClient::connect(&handle);
+        client.request(1, Message::Ping);
+        client.send(2, Message::Text("hello".to_string()));
+        client.request(3, Message::Ping);
+
+        let journaled: Vec<_> = Journal::read(dir.path())
+            .unwrap()
+            .map(|envelope| envelope.unwrap().message)
+            .collect();
+        assert_eq!(journaled.len(), 1);
+        assert!(matches!(&journaled[0], Message::Text(text) if text == "hello"));
+
+        handle.shutdown().unwrap();
+    }
+
+    #[test]
+    fn reloading_without_a_token_disconnects_its_connections() {
+        let tokens = |principals: &[&str]| {
+            let mut tokens = TokenStore::new();
+            for principal in principals {
+                tokens.insert(principal, &format!("{}-token", principal));
+            }
+            Some(tokens)
+        };
+        let mut server = Server::with_config(ServerConfig {
+            tokens: tokens(&["alice", "bob"]),
+            ..ServerConfig::default()
+        });
+        server.on_reload(move || {
+            Ok(ServerConfig {
+                tokens: tokens(&["alice"]),
+                ..ServerConfig::default()
+            })
+        });
+        let handle = start(server);
+
+        let mut alice = TestClient::connect(&handle);
+        alice.request(1, auth("alice-token"));
+        let mut bob = TestClient::connect(&handle);
+        bob.request(1, auth("bob-token"));
+
+        handle.reload_handle().reload().unwrap();
+
+        let error = loop {
+            if let Message::Error(error) = bob.recv().unwrap().message {
+                break error;
+            }
+        };
+        assert_eq!(error.code, ErrorCode::AuthenticationFailed);
+        match bob.recv().unwrap().message {
+            Message::Disconnect { reason } => assert_eq!(reason, DisconnectReason::TokenRevoked),
+            msg => panic!("unexpected message {}", msg),
+        }
+        bob.closed();
+
+        // alice's token is still valid
+        assert!(matches!(alice.request(2, Message::Ping), Message::Pong));
+        assert_eq!(handle.connections().len(), 1);
+
+        handle.shutdown().unwrap();
+    }
+
+    #[test]
+    fn published_events_never_contain_tokens() {
+        let mut tokens = TokenStore::new();
+        tokens.insert("alice", "alice-secret");
+        let mut admin_tokens = TokenStore::new();
+        admin_tokens.insert("alice", "alice-admin-secret");
+        let server = Server::with_config(ServerConfig {
+            tokens: Some(tokens),
+            admins: vec!["alice".to_string()].into_iter().collect(),
+            admin_tokens: Some(admin_tokens),
+            ..ServerConfig::default()
+        });
+        let events = server.subscribe();
+        let handle = start(server);
+
+        let mut alice = TestClient::connect(&handle);
+        alice.request(1, auth("alice-secret"));
+        alice.request(
+            2,
+            Message::AdminAuth {
+                token: "alice-admin-secret".to_string(),
+            },
+        );
+        // a wrong token is kept out of the events too
+        let mut mallory = TestClient::connect(&handle);
+        mallory.request(1, auth("mallory-secret"));
+        mallory.closed();
+        handle.shutdown().unwrap();
+
+        let events: Vec<_> = events.try_iter().collect();
+        let auths = events
+            .iter()
+            .filter(|event| {
+                matches!(
+                    event,
+                    ServerEvent::Received { envelope, .. }
+                        if matches!(envelope.message, Message::Auth { .. } | Message::AdminAuth { .. })
+                )
+            })
+            .count();
+        assert_eq!(auths, 3);
+        for event in &events {
+            let event = format!("{:?}", event);
+            assert!(!event.contains("secret"), "{}", event);
+        }
+    }
+
+    /// A server with `root` as its only admin, whose tokens are `<principal>-token` and
+    /// admin tokens `<principal>-admin-token`
+    fn admin_server() -> Server {
+        let mut tokens = TokenStore::new();
+        let mut admin_tokens = TokenStore::new();
+        for principal in &["root", "bob"] {
+            tokens.insert(principal, &format!("{}-token", principal));
+            admin_tokens.insert(principal, &format!("{}-admin-token", principal));
+        }
+
+        Server::with_config(ServerConfig {
+            tokens: Some(tokens),
+            admins: vec!["root".to_string()].into_iter().collect(),
+            admin_tokens: Some(admin_tokens),
+            ..ServerConfig::default()
+        })
+    }
+
+    fn admin_auth(token: &str) -> Message {
+        Message::AdminAuth {
+            token: token.to_string(),
+        }
+    }
+
+    /// Check that `client` was refused as an admin and disconnected
+    fn refused_admin(client: &mut TestClient, reply: Message) {
+        match reply {
+            Message::Error(error) => assert_eq!(error.code, ErrorCode::AuthenticationFailed),
+            msg => panic!("unexpected message {}", msg),
+        }
+        match client.recv().unwrap().message {
+            Message::Disconnect { reason } => {
+                assert_eq!(reason, DisconnectReason::AuthenticationFailed)
+            }
+            msg => panic!("unexpected message {}", msg),
+        }
+        client.closed();
+    }
+
+    #[test]
+    fn admins_are_elevated_by_their_admin_token() {
+        let handle = start(admin_server());
+
+        let mut root = TestClient::connect(&handle);
+        root.request(1, auth("root-token"));
+        assert!(matches!(
+            root.request(2, admin_auth("root-admin-token")),
+            Message::AdminAuthenticated(principal) if principal == "root"
+        ));
+        assert!(matches!(
+            root.request(3, Message::Admin(AdminRequest::Connections)),
+            Message::AdminReply(AdminReply::Connections(_))
+        ));
+
+        handle.shutdown().unwrap();
+    }
+
+    #[test]
+    fn a_principal_which_is_not_an_admin_is_refused() {
+        let handle = start(admin_server());
+
+        // bob may not send admin requests without elevating
+        let mut bob = TestClient::connect(&handle);
+        bob.request(1, auth("bob-token"));
+        match bob.request(2, Message::Admin(AdminRequest::Connections)) {
+            Message::Error(error) => assert_eq!(error.code, ErrorCode::NotAuthorised),
+            msg => panic!("unexpected message {}", msg),
+        }
+
+        // nor elevate with an admin token of his own, as he isn't one of the admins
+        let reply = bob.request(3, admin_auth("bob-admin-token"));
+        refused_admin(&mut bob, reply);
+
+        handle.shutdown().unwrap();
+    }
+
+    #[test]
+    fn a_wrong_admin_token_is_refused() {
+        let handle = start(admin_server());
+
+        let mut root = TestClient::connect(&handle);
+        root.request(1, auth("root-token"));
+        let reply = root.request(2, admin_auth("root-token"));
+        refused_admin(&mut root, reply);
+
+        // another principal's admin token doesn't elevate an admin either
+        let mut root = TestClient::connect(&handle);
+        root.request(1, auth("root-token"));
+        let reply = root.request(2, admin_auth("bob-admin-token"));
+        refused_admin(&mut root, reply);
+
+        // nor does an admin token before authenticating
+        let mut anonymous = TestClient::connect(&handle);
+        assert!(matches!(
+            anonymous.request(1, admin_auth("root-admin-token")),
+            Message::Error(_)
+        ));
+
+        handle.shutdown().unwrap();
+    }
+}
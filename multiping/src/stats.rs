@@ -0,0 +1,168 @@
+//! Counters describing the activity of a running server
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::connection::{ConnectionId, ConnectionRegistry};
+use crate::error::ErrorCode;
+use crate::message::{DisconnectReason, Envelope, Message};
+use crate::ratelimit::RateLimitStats;
+
+/// Statistics shared between the server and its connections' worker threads
+pub(crate) type SharedStats = Arc<Mutex<ServerStats>>;
+
+/// The bucket bounds, in seconds, of the latency histograms
+const LATENCY_BUCKETS: &[f64] = &[0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// The bucket bounds of the queue depth histogram
+const QUEUE_DEPTH_BUCKETS: &[f64] = &[0.0, 1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 500.0, 1000.0];
+
+/// The distribution of a series of observations
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Histogram {
+    /// The inclusive upper bounds of the buckets, in increasing order
+    pub bounds: Vec<f64>,
+    /// The number of observations in each bucket, with a last bucket for those above every bound
+    pub counts: Vec<u64>,
+    /// The total of all observations
+    pub sum: f64,
+    /// The number of observations
+    pub count: u64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &[f64]) -> Histogram {
+        Histogram {
+            bounds: bounds.to_vec(),
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    /// Count an observation in the first bucket whose bound it doesn't exceed
+    pub fn observe(&mut self, value: f64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|&bound| value <= bound)
+            .unwrap_or(self.bounds.len());
+
+        self.counts[bucket] += 1;
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// A snapshot of a server's counters
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ServerStats {
+    /// Connections accepted since the server started
+    pub connections_accepted: u64,
+    /// Connections currently open
+    pub connections_active: usize,
+    /// Connections refused by the admission limits
+    pub connections_rejected: u64,
+    /// Messages received from clients, by variant
+    pub messages_in: BTreeMap<String, u64>,
+    /// Messages sent to clients, by variant
+    pub messages_out: BTreeMap<String, u64>,
+    /// Bytes received from clients
+    pub bytes_in: u64,
+    /// Bytes sent to clients
+    pub bytes_out: u64,
+    /// Errors reported to clients, by code
+    pub errors: BTreeMap<ErrorCode, u64>,
+    /// Connections closed, by the reason they were disconnected
+    pub disconnects: BTreeMap<DisconnectReason, u64>,
+    /// Messages and connections affected by the rate limits
+    pub rate_limits: RateLimitStats,
+    /// The round trip time in milliseconds of each connection's last answered ping
+    pub rtt: BTreeMap<ConnectionId, u64>,
+    /// Messages received from clients which the server has yet to handle
+    pub queued: usize,
+    /// The number of messages waiting to be handled each time the server takes one
+    pub queue_depth: Histogram,
+    /// The time in seconds the server spent handling each message
+    pub dispatch_seconds: Histogram,
+    /// The round trip time in seconds of every ping the server sent
+    pub rtt_seconds: Histogram,
+}
+
+impl Default for ServerStats {
+    fn default() -> Self {
+        ServerStats {
+            connections_accepted: 0,
+            connections_active: 0,
+            connections_rejected: 0,
+            messages_in: BTreeMap::new(),
+            messages_out: BTreeMap::new(),
+            bytes_in: 0,
+            bytes_out: 0,
+            errors: BTreeMap::new(),
+            disconnects: BTreeMap::new(),
+            rate_limits: RateLimitStats::default(),
+            rtt: BTreeMap::new(),
+            queued: 0,
+            queue_depth: Histogram::new(QUEUE_DEPTH_BUCKETS),
+            dispatch_seconds: Histogram::new(LATENCY_BUCKETS),
+            rtt_seconds: Histogram::new(LATENCY_BUCKETS),
+        }
+    }
+}
+
+impl ServerStats {
+    /// Count a received message taken off the queue by the server, recording how many were
+    /// waiting
+    pub(crate) fn dequeued(&mut self) {
+        self.queued = self.queued.saturating_sub(1);
+        self.queue_depth.observe(self.queued as f64);
+    }
+
+    /// Count a message received from a client
+    pub(crate) fn record_in(&mut self, envelope: &Envelope, bytes: usize) {
+        *self
+            .messages_in
+            .entry(envelope.message.kind().to_string())
+            .or_default() += 1;
+        self.bytes_in += bytes as u64;
+    }
+
+    /// Count a message sent to a client
+    pub(crate) fn record_out(&mut self, envelope: &Envelope, bytes: usize) {
+        *self
+            .messages_out
+            .entry(envelope.message.kind().to_string())
+            .or_default() += 1;
+        self.bytes_out += bytes as u64;
+
+        if let Message::Error(e) = &envelope.message {
+            *self.errors.entry(e.code).or_default() += 1;
+        }
+    }
+}
+
+/// A handle for reading a server's statistics from another thread while it runs
+#[derive(Debug, Clone)]
+pub struct StatsHandle {
+    pub(crate) connections: Arc<Mutex<ConnectionRegistry>>,
+    pub(crate) stats: SharedStats,
+}
+
+impl StatsHandle {
+    /// Take a snapshot of the server's statistics
+    pub fn stats(&self) -> ServerStats {
+        let conns = self.connections.lock().expect("mutex poisoned");
+        let mut stats = self.stats.lock().expect("mutex poisoned").clone();
+
+        stats.connections_active = conns.len();
+        stats.rtt = conns
+            .rtts()
+            .map(|(id, rtt)| (id, rtt.as_millis() as u64))
+            .collect();
+
+        stats
+    }
+}
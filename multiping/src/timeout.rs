@@ -0,0 +1,165 @@
+//! Closing connections which have been idle or open for too long
+
+use std::time::{Duration, Instant};
+
+use crate::message::DisconnectReason;
+
+/// How long before a connection times out it is warned, by default
+pub const DEFAULT_TIMEOUT_WARNING: Duration = Duration::from_secs(30);
+
+/// How long the connections accepted by a listener may stay open
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timeouts {
+    /// How long a connection may go without sending or being sent a message, other than
+    /// the pings measuring its round trip time
+    pub idle: Option<Duration>,
+
+    /// How long a connection may stay open, however active it is
+    pub session: Option<Duration>,
+
+    /// How long before a connection times out it is sent a [`crate::Message::DisconnectWarning`]
+    pub warning: Duration,
+}
+
+impl Timeouts {
+    /// Whether connections time out at all
+    pub fn is_enabled(&self) -> bool {
+        self.idle.is_some() || self.session.is_some()
+    }
+
+    /// When a connection opened at `connected_at` and last active at `last_active` times
+    /// out, and why, if it ever does
+    pub(crate) fn deadline(
+        &self,
+        connected_at: Instant,
+        last_active: Instant,
+    ) -> Option<(Instant, DisconnectReason)> {
+        let idle = self
+            .idle
+            .map(|idle| (last_active + idle, DisconnectReason::IdleTimeout));
+        let session = self
+            .session
+            .map(|session| (connected_at + session, DisconnectReason::SessionExpired));
+
+        match (idle, session) {
+            (Some(idle), Some(session)) => Some(if session.0 <= idle.0 { session } else { idle }),
+            (idle, session) => idle.or(session),
+        }
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            idle: None,
+            session: None,
+            warning: DEFAULT_TIMEOUT_WARNING,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timeouts(idle: Option<u64>, session: Option<u64>) -> Timeouts {
+        Timeouts {
+            idle: idle.map(Duration::from_secs),
+            session: session.map(Duration::from_secs),
+            ..Timeouts::default()
+        }
+    }
+
+    #[test]
+    fn no_timeouts_never_expire() {
+        let now = Instant::now();
+        let timeouts = timeouts(None, None);
+
+        assert!(!timeouts.is_enabled());
+        assert_eq!(timeouts.deadline(now, now), None);
+    }
+
+    #[test]
+    fn idle_deadline_follows_the_last_activity() {
+        let connected_at = Instant::now();
+        let last_active = connected_at + Duration::from_secs(100);
+
+        assert_eq!(
+            timeouts(Some(10), None).deadline(connected_at, last_active),
+            Some((
+                last_active + Duration::from_secs(10),
+                DisconnectReason::IdleTimeout
+            ))
+        );
+    }
+
+    #[test]
+    fn session_deadline_follows_the_connection_time() {
+        let connected_at = Instant::now();
+        let last_active = connected_at + Duration::from_secs(100);
+
+        assert_eq!(
+            timeouts(None, Some(60)).deadline(connected_at, last_active),
+            Some((
+                connected_at + Duration::from_secs(60),
+                DisconnectReason::SessionExpired
+            ))
+        );
+    }
+
+    #[test]
+    fn earliest_deadline_wins() {
+        let connected_at = Instant::now();
+        let last_active = connected_at + Duration::from_secs(100);
+
+        // idle until 110, session until 200
+        assert_eq!(
+            timeouts(Some(10), Some(200)).deadline(connected_at, last_active),
+            Some((
+                last_active + Duration::from_secs(10),
+                DisconnectReason::IdleTimeout
+            ))
+        );
+        // idle until 110, session until 50
+        assert_eq!(
+            timeouts(Some(10), Some(50)).deadline(connected_at, last_active),
+            Some((
+                connected_at + Duration::from_secs(50),
+                DisconnectReason::SessionExpired
+            ))
+        );
+    }
+
+    #[test]
+    fn session_wins_a_tie() {
+        let now = Instant::now();
+
+        assert_eq!(
+            timeouts(Some(30), Some(30)).deadline(now, now),
+            Some((
+                now + Duration::from_secs(30),
+                DisconnectReason::SessionExpired
+            ))
+        );
+    }
+
+    #[test]
+    fn zero_timeouts_expire_straight_away() {
+        let connected_at = Instant::now();
+        let last_active = connected_at + Duration::from_secs(5);
+
+        assert!(timeouts(Some(0), None).is_enabled());
+        assert_eq!(
+            timeouts(Some(0), None).deadline(connected_at, last_active),
+            Some((last_active, DisconnectReason::IdleTimeout))
+        );
+        assert_eq!(
+            timeouts(None, Some(0)).deadline(connected_at, last_active),
+            Some((connected_at, DisconnectReason::SessionExpired))
+        );
+        assert_eq!(
+            timeouts(Some(0), Some(0)).deadline(connected_at, last_active),
+            Some((connected_at, DisconnectReason::SessionExpired))
+        );
+    }
+}
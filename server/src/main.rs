@@ -1,32 +1,386 @@
 #[macro_use]
-extern crate log;
+extern crate tracing;
+
+mod metrics;
+mod settings;
+
+use std::sync::Arc;
+#[cfg(unix)]
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use clap::{App, Arg};
+#[cfg(unix)]
+use signal_hook::{consts::SIGHUP, iterator::Signals};
+use tracing_subscriber::EnvFilter;
 
-use multiping::Server;
+#[cfg(unix)]
+use multiping::ReloadHandle;
+use multiping::{
+    AdmissionConfig, BanList, Caller, HistoryConfig, Journal, JournalConfig, ListenerConfig,
+    MailboxConfig, RateLimitConfig, ReloadedConfig, Server, ServerConfig, Timeouts, TokenStore,
+};
 
-fn main() {
-    env_logger::init();
+use crate::settings::Settings;
 
-    debug!("parse cli args");
+/// The formats log output can be written in
+const LOG_FORMATS: [&str; 2] = ["text", "json"];
 
+/// The settings, besides those in [`ServerConfig`], which can't change while the server runs
+const RESTART_SETTINGS: [&str; 7] = [
+    "address",
+    "trusted-address",
+    "idle-timeout",
+    "session-timeout",
+    "timeout-warning",
+    "metrics",
+    "log-format",
+];
+
+/// Replaces the filter on log output
+type SetLogFilter = Arc<dyn Fn(EnvFilter) + Send + Sync>;
+
+fn main() {
     let matches = App::new("Multiping Server")
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("FILE")
+                .help("Reads settings from the given TOML file, which flags and environment variables override")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("address")
                 .short("a")
                 .long("address")
-                .help("Sets the adress to bind the server to")
+                .value_name("ADDRESS")
+                .help("Listens on the given host:port or unix:<path>")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("trusted-address")
+                .long("trusted-address")
+                .value_name("ADDRESS")
+                .help("Listens on the given host:port or unix:<path>, without requiring a token")
                 .takes_value(true)
-                .required(true),
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("tokens")
+                .short("t")
+                .long("tokens")
+                .help("Requires clients to authenticate with a token from the given file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bans")
+                .long("bans")
+                .value_name("FILE")
+                .help("Keeps the addresses and principals banned by admins in the given file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("conn-rate")
+                .long("conn-rate")
+                .value_name("MESSAGES:BYTES")
+                .help("Limits the messages and bytes per second of each connection")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ip-rate")
+                .long("ip-rate")
+                .value_name("MESSAGES:BYTES")
+                .help("Limits the messages and bytes per second from each IP address")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rate-limit-action")
+                .long("rate-limit-action")
+                .help("What to do with messages over a rate limit")
+                .possible_values(&["drop", "warn", "disconnect"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-connections")
+                .long("max-connections")
+                .help("Limits the number of simultaneous connections")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-per-ip")
+                .long("max-per-ip")
+                .help("Limits the number of simultaneous connections from each IP address")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("allow")
+                .long("allow")
+                .value_name("CIDR")
+                .help("Only accepts connections from the given address ranges")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("deny")
+                .long("deny")
+                .value_name("CIDR")
+                .help("Refuses connections from the given address ranges")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("max-message-size")
+                .long("max-message-size")
+                .value_name("BYTES")
+                .help("Disconnects clients which send messages larger than this")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-offences")
+                .long("max-offences")
+                .help("Disconnects clients after this many malformed or unexpected messages")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("history")
+                .long("history")
+                .value_name("MESSAGES")
+                .help("Retains this many messages to replay to clients which join late")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("history-age")
+                .long("history-age")
+                .value_name("SECONDS")
+                .help("Forgets retained messages after this long")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("mailbox")
+                .long("mailbox")
+                .value_name("MESSAGES")
+                .help("Holds this many direct messages for each offline client")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("mailbox-ttl")
+                .long("mailbox-ttl")
+                .value_name("SECONDS")
+                .help("Drops held direct messages after this long")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-unacked")
+                .long("max-unacked")
+                .value_name("MESSAGES")
+                .help("Retransmits this many unacknowledged messages to each client when it reconnects")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ping-interval")
+                .long("ping-interval")
+                .value_name("SECONDS")
+                .help("Pings clients this often to measure their round trip time, or never if 0")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("idle-timeout")
+                .long("idle-timeout")
+                .value_name("SECONDS")
+                .help("Disconnects clients which neither send nor are sent a message for this long")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("session-timeout")
+                .long("session-timeout")
+                .value_name("SECONDS")
+                .help("Disconnects clients which have been connected for this long")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("timeout-warning")
+                .long("timeout-warning")
+                .value_name("SECONDS")
+                .help("Warns clients this long before they time out [default: 30]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("stats-access")
+                .long("stats-access")
+                .value_name("PRINCIPAL")
+                .help("Only lets the given principals request the server's statistics")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("admin")
+                .long("admin")
+                .value_name("PRINCIPAL")
+                .help("Lets the given principals administer the server, e.g. reload its config")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("admin-tokens")
+                .long("admin-tokens")
+                .value_name("FILE")
+                .help("Grants admins their rights once they present a token from the given file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("metrics")
+                .long("metrics")
+                .value_name("ADDRESS")
+                .help("Serves Prometheus metrics on /metrics at the given address")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("journal")
+                .long("journal")
+                .value_name("DIR")
+                .help("Durably logs every routed message to the given directory")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("journal-sync")
+                .long("journal-sync")
+                .value_name("POLICY")
+                .help("When to flush the journal to disk: always, never or every:<n> messages")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("journal-segment-size")
+                .long("journal-segment-size")
+                .value_name("BYTES")
+                .help("Starts a new journal file once the current one reaches this size")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("journal-max-size")
+                .long("journal-max-size")
+                .value_name("BYTES")
+                .help("Deletes the oldest journal files to stay within this size")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("journal-max-age")
+                .long("journal-max-age")
+                .value_name("SECONDS")
+                .help("Deletes journal files older than this")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dump-journal")
+                .long("dump-journal")
+                .value_name("DIR")
+                .help("Prints the messages in a journal and exits")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .long("log-format")
+                .help("How to format log output")
+                .possible_values(&LOG_FORMATS)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-filter")
+                .long("log-filter")
+                .value_name("DIRECTIVES")
+                .help("Which logs to output, in the syntax of RUST_LOG, which it overrides")
+                .takes_value(true),
         )
         .get_matches();
 
-    debug!("cli args parsed successfully");
+    let settings = Settings::load(matches);
+
+    // a bad filter is reported with the rest of the invalid settings below
+    let set_log_filter = match &settings {
+        Ok(settings) => init_logging(
+            settings.value_of("log-format").as_deref() == Some("json"),
+            log_filter(settings).unwrap_or_else(|_| EnvFilter::from_default_env()),
+        ),
+        Err(_) => init_logging(false, EnvFilter::from_default_env()),
+    };
+
+    let settings = match settings {
+        Ok(settings) => settings,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+
+    debug!("settings loaded successfully");
+
+    if let Some(dir) = settings.value_of("dump-journal") {
+        if let Err(e) = dump_journal(&dir) {
+            error!("failed to read journal in {}: {}", dir, e);
+        }
+        return;
+    }
+
+    let config = match build_config(&settings) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+
+    let listeners = match parse_listeners(&settings) {
+        Ok(listeners) => listeners,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+
+    let mut server = Server::with_config(config);
+    register_commands(&mut server);
+
+    if let Some(metrics_addr) = settings.value_of("metrics") {
+        if let Err(e) = metrics::serve(&metrics_addr, server.stats_handle()) {
+            error!("failed to serve metrics on {}: {}", metrics_addr, e);
+            return;
+        }
+    }
+
+    #[cfg(unix)]
+    if let Err(e) = reload_on_hangup(server.reload_handle()) {
+        error!("failed to listen for SIGHUP: {}", e);
+        return;
+    }
+    let set_admin_log_filter = set_log_filter.clone();
+    server.on_log_filter(move |directives| {
+        let filter = EnvFilter::try_new(directives)
+            .map_err(|e| multiping::Error::InvalidArguments(format!("log filter: {}", e)))?;
+        set_admin_log_filter(filter);
+        Ok(())
+    });
+    server.on_reload(move || reload(&settings, &set_log_filter));
 
-    let addr = matches.value_of("address").unwrap();
+    for listener in listeners {
+        let addr = listener.addr.clone();
+        match server.listen(listener) {
+            Ok(local_addr) => println!("Server running on {}", local_addr),
+            Err(e) => {
+                error!("failed to listen on {}: {}", addr, e);
+                return;
+            }
+        }
+    }
 
     debug!("run server");
-    match Server::new().run(addr) {
+    match server.serve() {
         Ok(()) => {
             info!("server exited successfully.");
         }
@@ -35,3 +389,325 @@ fn main() {
         }
     }
 }
+
+/// Log to stderr through `filter`, as JSON objects if `json` is set or text otherwise
+///
+/// Returns a function which replaces the filter.
+fn init_logging(json: bool, filter: EnvFilter) -> SetLogFilter {
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+
+    // the reload handle's type depends on the format, so erase it
+    if json {
+        let subscriber = subscriber.json().with_filter_reloading();
+        let handle = subscriber.reload_handle();
+        subscriber.init();
+        Arc::new(move |filter| {
+            if let Err(e) = handle.reload(filter) {
+                error!("failed to change the log filter: {}", e);
+            }
+        })
+    } else {
+        let subscriber = subscriber.with_filter_reloading();
+        let handle = subscriber.reload_handle();
+        subscriber.init();
+        Arc::new(move |filter| {
+            if let Err(e) = handle.reload(filter) {
+                error!("failed to change the log filter: {}", e);
+            }
+        })
+    }
+}
+
+/// The filter on log output, from `--log-filter` or else `RUST_LOG`
+fn log_filter(settings: &Settings) -> multiping::Result<EnvFilter> {
+    match settings.value_of("log-filter") {
+        Some(directives) => EnvFilter::try_new(directives).map_err(|e| {
+            multiping::Error::InvalidConfig(format!("{}: {}", settings.describe("log-filter"), e))
+        }),
+        None => Ok(EnvFilter::from_default_env()),
+    }
+}
+
+/// Read the settings again for a running server, changing the log filter straight away
+///
+/// Changes to the [`RESTART_SETTINGS`] are reported by their flag as needing a restart.
+fn reload(settings: &Settings, set_log_filter: &SetLogFilter) -> multiping::Result<ReloadedConfig> {
+    let reloaded = settings.reload()?;
+    let config = build_config(&reloaded)?;
+    set_log_filter(log_filter(&reloaded)?);
+
+    let mut restart_required: Vec<_> = RESTART_SETTINGS
+        .iter()
+        .filter(|name| reloaded.values_of(name) != settings.values_of(name))
+        .map(|name| name.to_string())
+        .collect();
+    if reloaded.listeners() != settings.listeners() {
+        restart_required.push("listeners".to_string());
+    }
+
+    Ok(ReloadedConfig {
+        config,
+        restart_required,
+    })
+}
+
+/// Ask the server to reload its config whenever the process receives SIGHUP
+#[cfg(unix)]
+fn reload_on_hangup(handle: ReloadHandle) -> std::io::Result<()> {
+    let mut signals = Signals::new([SIGHUP])?;
+
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            info!("received SIGHUP, reloading config");
+            if handle.reload().is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Register the diagnostic commands clients can invoke
+fn register_commands(server: &mut Server) {
+    // the server's clock, in seconds since the unix epoch
+    server.register_command("time", |_: &Caller, ()| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .map_err(|e| multiping::Error::CommandFailed(e.to_string()))
+    });
+
+    // return the arguments unchanged
+    server.register_command("echo", |_: &Caller, args: serde_json::Value| Ok(args));
+
+    // a snapshot of the server's statistics, for the callers allowed to send a Stats message
+    let stats = server.stats_handle();
+    server.register_command("stats", move |caller: &Caller, ()| {
+        if caller.stats_access {
+            Ok(stats.stats())
+        } else {
+            Err(multiping::Error::NotAuthorised)
+        }
+    });
+}
+
+/// Build the server's configuration from the settings, rejecting invalid or contradictory ones
+fn build_config(settings: &Settings) -> multiping::Result<ServerConfig> {
+    if let Some(format) = settings.value_of("log-format") {
+        if !LOG_FORMATS.contains(&format.as_str()) {
+            return Err(multiping::Error::InvalidConfig(format!(
+                "{} must be one of {}",
+                settings.describe("log-format"),
+                LOG_FORMATS.join(", ")
+            )));
+        }
+    }
+
+    // options which do nothing without the option they refine
+    let dependents = [
+        ("history-age", "history"),
+        ("mailbox-ttl", "mailbox"),
+        ("journal-sync", "journal"),
+        ("journal-segment-size", "journal"),
+        ("journal-max-size", "journal"),
+        ("journal-max-age", "journal"),
+    ];
+    for (dependent, required) in &dependents {
+        if settings.value_of(dependent).is_some() && settings.value_of(required).is_none() {
+            return Err(multiping::Error::InvalidConfig(format!(
+                "{} has no effect without --{}",
+                settings.describe(dependent),
+                required
+            )));
+        }
+    }
+
+    let mut config = ServerConfig::default();
+
+    if let Some(path) = settings.value_of("tokens") {
+        let tokens = TokenStore::load(&path).map_err(|e| {
+            multiping::Error::InvalidConfig(format!("failed to load tokens from {}: {}", path, e))
+        })?;
+        config.tokens = Some(tokens);
+    }
+
+    if let Some(path) = settings.value_of("bans") {
+        config.bans = BanList::load(&path).map_err(|e| {
+            multiping::Error::InvalidConfig(format!("failed to load bans from {}: {}", path, e))
+        })?;
+    }
+
+    config.rate_limits = parse_rate_limits(settings)?;
+    config.admission = parse_admission(settings)?;
+    if let Some(size) = settings.number("max-message-size")? {
+        config.max_message_size = size;
+    }
+    if let Some(max) = settings.number("max-offences")? {
+        config.max_offences = max;
+    }
+    config.history = parse_history(settings)?;
+    if let Some(max) = settings.number("max-unacked")? {
+        config.max_unacked = max;
+    }
+    match settings.number("ping-interval")? {
+        Some(0) => config.ping_interval = None,
+        Some(secs) => config.ping_interval = Some(Duration::from_secs(secs)),
+        None => {}
+    }
+    log_filter(settings)?;
+
+    let stats_access = settings.values_of("stats-access");
+    if !stats_access.is_empty() {
+        config.stats_access = Some(stats_access.into_iter().collect());
+    }
+    config.admins = settings.values_of("admin").into_iter().collect();
+    if let Some(path) = settings.value_of("admin-tokens") {
+        let tokens = TokenStore::load(&path).map_err(|e| {
+            multiping::Error::InvalidConfig(format!(
+                "failed to load admin tokens from {}: {}",
+                path, e
+            ))
+        })?;
+        config.admin_tokens = Some(tokens);
+    }
+    config.mailbox = parse_mailbox(settings)?;
+    config.journal = parse_journal(settings)?;
+
+    Ok(config)
+}
+
+/// Read the addresses to listen on
+fn parse_listeners(settings: &Settings) -> multiping::Result<Vec<ListenerConfig>> {
+    let timeouts = parse_timeouts(settings)?;
+
+    let mut listeners: Vec<_> = settings
+        .parse_all("address")?
+        .into_iter()
+        .map(|addr| ListenerConfig {
+            timeouts,
+            ..ListenerConfig::new(addr)
+        })
+        .collect();
+    for addr in settings.parse_all("trusted-address")? {
+        listeners.push(ListenerConfig {
+            addr,
+            require_auth: false,
+            timeouts,
+        });
+    }
+    for (index, listener) in settings.listeners().iter().enumerate() {
+        let addr =
+            listener.address.parse().map_err(|e| match e {
+                multiping::Error::InvalidConfig(reason) => multiping::Error::InvalidConfig(
+                    format!("{}: {}", settings.describe_listener(index), reason),
+                ),
+                e => e,
+            })?;
+        let mut timeouts = timeouts;
+        if let Some(secs) = listener.idle_timeout {
+            timeouts.idle = Some(Duration::from_secs(secs));
+        }
+        if let Some(secs) = listener.session_timeout {
+            timeouts.session = Some(Duration::from_secs(secs));
+        }
+
+        listeners.push(ListenerConfig {
+            addr,
+            require_auth: !listener.trusted,
+            timeouts,
+        });
+    }
+
+    if listeners.is_empty() {
+        return Err(multiping::Error::InvalidConfig(
+            "no address to listen on, pass --address or give one in the config file".to_string(),
+        ));
+    }
+
+    Ok(listeners)
+}
+
+/// Read the connection timeout options, which apply to every listener which doesn't set its
+/// own
+fn parse_timeouts(settings: &Settings) -> multiping::Result<Timeouts> {
+    let mut timeouts = Timeouts {
+        idle: settings.number("idle-timeout")?.map(Duration::from_secs),
+        session: settings.number("session-timeout")?.map(Duration::from_secs),
+        ..Timeouts::default()
+    };
+    if let Some(secs) = settings.number("timeout-warning")? {
+        timeouts.warning = Duration::from_secs(secs);
+    }
+
+    Ok(timeouts)
+}
+
+/// Read the rate limit options
+fn parse_rate_limits(settings: &Settings) -> multiping::Result<RateLimitConfig> {
+    Ok(RateLimitConfig {
+        per_connection: settings.parse("conn-rate")?,
+        per_ip: settings.parse("ip-rate")?,
+        action: settings.parse("rate-limit-action")?.unwrap_or_default(),
+    })
+}
+
+/// Read the connection admission options
+fn parse_admission(settings: &Settings) -> multiping::Result<AdmissionConfig> {
+    Ok(AdmissionConfig {
+        max_connections: settings.number("max-connections")?,
+        max_per_ip: settings.number("max-per-ip")?,
+        allow: settings.parse_all("allow")?,
+        deny: settings.parse_all("deny")?,
+    })
+}
+
+/// Read the message history options
+fn parse_history(settings: &Settings) -> multiping::Result<HistoryConfig> {
+    Ok(HistoryConfig {
+        capacity: settings.number("history")?.unwrap_or(0),
+        max_age: settings.number("history-age")?.map(Duration::from_secs),
+    })
+}
+
+/// Read the offline mailbox options
+fn parse_mailbox(settings: &Settings) -> multiping::Result<MailboxConfig> {
+    Ok(MailboxConfig {
+        capacity: settings.number("mailbox")?.unwrap_or(0),
+        ttl: settings.number("mailbox-ttl")?.map(Duration::from_secs),
+    })
+}
+
+/// Read the journal options
+fn parse_journal(settings: &Settings) -> multiping::Result<Option<JournalConfig>> {
+    let dir = match settings.value_of("journal") {
+        Some(dir) => dir,
+        None => return Ok(None),
+    };
+
+    let mut config = JournalConfig::new(dir);
+    if let Some(sync) = settings.parse("journal-sync")? {
+        config.sync = sync;
+    }
+    if let Some(size) = settings.number("journal-segment-size")? {
+        config.segment_size = size;
+    }
+    config.max_size = settings.number("journal-max-size")?;
+    config.max_age = settings.number("journal-max-age")?.map(Duration::from_secs);
+
+    Ok(Some(config))
+}
+
+/// Print every message in the journal in `dir`, one JSON envelope per line
+fn dump_journal(dir: &str) -> multiping::Result<()> {
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+
+    for envelope in Journal::read(dir)? {
+        envelope?.write(&mut stdout)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,410 @@
+//! A minimal HTTP endpoint publishing the server's statistics for Prometheus to scrape
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use multiping::{Histogram, ServerStats, StatsHandle};
+
+/// How long a scraper has to send its whole request, and to read each write of the response
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The longest request or header line accepted, in bytes
+const MAX_LINE_LENGTH: u64 = 8 * 1024;
+
+/// The most headers accepted in a request
+const MAX_HEADERS: usize = 100;
+
+/// Serve the statistics behind `handle` on `GET /metrics` at `addr`
+pub fn serve(addr: &str, handle: StatsHandle) -> multiping::Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(addr)?;
+    info!("serving metrics on {}", addr);
+
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream
+                .map_err(multiping::Error::from)
+                .and_then(|stream| respond(stream, &handle));
+            if let Err(e) = result {
+                warn!("failed to serve metrics: {}", e);
+            }
+        }
+    }))
+}
+
+/// Answer a single HTTP request
+///
+/// Requests are served one at a time, so one which is too slow or too long is dropped
+/// rather than holding up the scrapers behind it.
+fn respond(mut stream: TcpStream, handle: &StatsHandle) -> multiping::Result<()> {
+    let deadline = Instant::now() + REQUEST_TIMEOUT;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let request = read_line(&mut reader, deadline)?;
+
+    // skip the headers, the request line is all that matters
+    let mut headers = 0;
+    while !read_line(&mut reader, deadline)?.trim_end().is_empty() {
+        headers += 1;
+        if headers > MAX_HEADERS {
+            return Err(invalid_request("too many headers"));
+        }
+    }
+
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render(&handle.stats())),
+        _ => ("404 Not Found", "not found\n".to_string()),
+    };
+    debug!("metrics request {:?}: {}", request.trim(), status);
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+
+    Ok(())
+}
+
+/// Read a line of the request, failing if it is too long or doesn't arrive by `deadline`
+fn read_line(reader: &mut BufReader<TcpStream>, deadline: Instant) -> multiping::Result<String> {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.is_zero() {
+        return Err(io::Error::new(io::ErrorKind::TimedOut, "request timed out").into());
+    }
+    reader.get_ref().set_read_timeout(Some(remaining))?;
+
+    let mut line = Vec::new();
+    reader
+        .by_ref()
+        .take(MAX_LINE_LENGTH)
+        .read_until(b'\n', &mut line)?;
+    if line.last() != Some(&b'\n') {
+        return Err(invalid_request("line too long or incomplete"));
+    }
+
+    Ok(String::from_utf8_lossy(&line).into_owned())
+}
+
+/// The error for a request which won't be answered
+fn invalid_request(reason: &str) -> multiping::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("bad request: {}", reason),
+    )
+    .into()
+}
+
+/// Format the statistics in the Prometheus text exposition format
+fn render(stats: &ServerStats) -> String {
+    let mut out = String::new();
+
+    let singles = [
+        (
+            "connections_active",
+            "gauge",
+            "Open connections",
+            stats.connections_active as u64,
+        ),
+        (
+            "connections_accepted_total",
+            "counter",
+            "Connections accepted",
+            stats.connections_accepted,
+        ),
+        (
+            "connections_rejected_total",
+            "counter",
+            "Connections refused",
+            stats.connections_rejected,
+        ),
+        (
+            "bytes_received_total",
+            "counter",
+            "Bytes received from clients",
+            stats.bytes_in,
+        ),
+        (
+            "bytes_sent_total",
+            "counter",
+            "Bytes sent to clients",
+            stats.bytes_out,
+        ),
+        (
+            "rate_limited_total",
+            "counter",
+            "Messages over a rate limit",
+            stats.rate_limits.limited,
+        ),
+        (
+            "rate_limit_disconnects_total",
+            "counter",
+            "Connections disconnected for exceeding a rate limit",
+            stats.rate_limits.disconnected,
+        ),
+        (
+            "queued_messages",
+            "gauge",
+            "Messages waiting to be handled",
+            stats.queued as u64,
+        ),
+    ];
+    for (name, kind, help, value) in &singles {
+        single(&mut out, name, kind, help, *value);
+    }
+
+    let errors = stats
+        .errors
+        .iter()
+        .map(|(code, count)| (code.to_string(), *count))
+        .collect();
+    let disconnects = stats
+        .disconnects
+        .iter()
+        .map(|(reason, count)| (reason.to_string(), *count))
+        .collect();
+    let labelled_counters = [
+        (
+            "messages_received_total",
+            "Messages received from clients",
+            "kind",
+            &stats.messages_in,
+        ),
+        (
+            "messages_sent_total",
+            "Messages sent to clients",
+            "kind",
+            &stats.messages_out,
+        ),
+        (
+            "errors_total",
+            "Errors reported to clients",
+            "code",
+            &errors,
+        ),
+        (
+            "disconnects_total",
+            "Connections closed",
+            "reason",
+            &disconnects,
+        ),
+    ];
+    for (name, help, label, counts) in &labelled_counters {
+        labelled(&mut out, name, help, label, counts);
+    }
+
+    let histograms = [
+        (
+            "queue_depth",
+            "Messages waiting each time one is handled",
+            &stats.queue_depth,
+        ),
+        (
+            "dispatch_duration_seconds",
+            "Time spent handling each message",
+            &stats.dispatch_seconds,
+        ),
+        (
+            "ping_rtt_seconds",
+            "Round trip time of pings to clients",
+            &stats.rtt_seconds,
+        ),
+    ];
+    for (name, help, values) in &histograms {
+        histogram(&mut out, name, help, values);
+    }
+
+    out
+}
+
+/// Write the help and type lines of a metric
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP multiping_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE multiping_{} {}", name, kind);
+}
+
+/// Write a metric with a single unlabelled sample
+fn single(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    header(out, name, kind, help);
+    let _ = writeln!(out, "multiping_{} {}", name, value);
+}
+
+/// Write a counter with a sample for each value of `label`
+fn labelled(out: &mut String, name: &str, help: &str, label: &str, counts: &BTreeMap<String, u64>) {
+    header(out, name, "counter", help);
+    for (key, count) in counts {
+        let _ = writeln!(out, "multiping_{}{{{}=\"{}\"}} {}", name, label, key, count);
+    }
+}
+
+/// Write a histogram, whose buckets Prometheus expects to be cumulative
+fn histogram(out: &mut String, name: &str, help: &str, histogram: &Histogram) {
+    header(out, name, "histogram", help);
+
+    let mut cumulative = 0;
+    for (bound, count) in histogram.bounds.iter().zip(&histogram.counts) {
+        cumulative += count;
+        let _ = writeln!(
+            out,
+            "multiping_{}_bucket{{le=\"{}\"}} {}",
+            name, bound, cumulative
+        );
+    }
+    let _ = writeln!(
+        out,
+        "multiping_{}_bucket{{le=\"+Inf\"}} {}",
+        name, histogram.count
+    );
+    let _ = writeln!(out, "multiping_{}_sum {}", name, histogram.sum);
+    let _ = writeln!(out, "multiping_{}_count {}", name, histogram.count);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Shutdown;
+
+    use multiping::{DisconnectReason, ErrorCode, Server};
+
+    /// Send `request` to a metrics endpoint, returning what `respond` made of it and the
+    /// response
+    fn exchange(request: &[u8]) -> (multiping::Result<()>, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        // the server may give up before the whole request is written
+        let _ = client.write_all(request);
+        let _ = client.shutdown(Shutdown::Write);
+        let result = respond(stream, &Server::new().stats_handle());
+
+        let mut response = String::new();
+        let _ = client.read_to_string(&mut response);
+        (result, response)
+    }
+
+    #[test]
+    fn metrics_are_served() {
+        let (result, response) =
+            exchange(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nAccept: */*\r\n\r\n");
+
+        result.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("\r\n\r\n# HELP multiping_connections_active "));
+    }
+
+    #[test]
+    fn other_paths_are_not_found() {
+        let (result, response) = exchange(b"GET / HTTP/1.1\r\n\r\n");
+
+        result.unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[test]
+    fn overlong_lines_are_refused() {
+        let mut request = b"GET /metrics HTTP/1.1\r\nCookie: ".to_vec();
+        request.extend(std::iter::repeat_n(b'x', MAX_LINE_LENGTH as usize));
+        request.extend_from_slice(b"\r\n\r\n");
+
+        let (result, response) = exchange(&request);
+        assert!(result.is_err());
+        assert!(response.is_empty());
+    }
+
+    #[test]
+    fn endless_headers_are_refused() {
+        let mut request = b"GET /metrics HTTP/1.1\r\n".to_vec();
+        for _ in 0..=MAX_HEADERS {
+            request.extend_from_slice(b"X-Padding: x\r\n");
+        }
+        request.extend_from_slice(b"\r\n");
+
+        let (result, response) = exchange(&request);
+        assert!(result.is_err());
+        assert!(response.is_empty());
+    }
+
+    #[test]
+    fn incomplete_requests_are_refused() {
+        // the scraper stops sending part way through the request line
+        let (result, response) = exchange(b"GET /metr");
+
+        assert!(result.is_err());
+        assert!(response.is_empty());
+    }
+
+    #[test]
+    fn render_uses_the_exposition_format() {
+        let mut stats = ServerStats {
+            connections_active: 2,
+            connections_accepted: 5,
+            ..ServerStats::default()
+        };
+        stats.messages_in.insert("Text".to_string(), 7);
+        stats.errors.insert(ErrorCode::RateLimited, 3);
+        stats.disconnects.insert(DisconnectReason::IdleTimeout, 1);
+        stats.rtt_seconds = Histogram::new(&[0.1, 1.0]);
+        for rtt in &[0.05, 0.5, 0.6, 2.0] {
+            stats.rtt_seconds.observe(*rtt);
+        }
+
+        let out = render(&stats);
+        let lines: Vec<&str> = out.lines().collect();
+        for expected in &[
+            "# HELP multiping_connections_active Open connections",
+            "# TYPE multiping_connections_active gauge",
+            "multiping_connections_active 2",
+            "# TYPE multiping_connections_accepted_total counter",
+            "multiping_connections_accepted_total 5",
+            "multiping_messages_received_total{kind=\"Text\"} 7",
+            "multiping_errors_total{code=\"RateLimited\"} 3",
+            "# TYPE multiping_ping_rtt_seconds histogram",
+            // buckets are cumulative, ending with every observation
+            "multiping_ping_rtt_seconds_bucket{le=\"0.1\"} 1",
+            "multiping_ping_rtt_seconds_bucket{le=\"1\"} 3",
+            "multiping_ping_rtt_seconds_bucket{le=\"+Inf\"} 4",
+            "multiping_ping_rtt_seconds_sum 3.15",
+            "multiping_ping_rtt_seconds_count 4",
+        ] {
+            assert!(
+                lines.contains(expected),
+                "missing {:?} in\n{}",
+                expected,
+                out
+            );
+        }
+        assert!(out.contains(&format!(
+            "multiping_disconnects_total{{reason=\"{}\"}} 1",
+            DisconnectReason::IdleTimeout
+        )));
+
+        // every sample follows the help and type of its metric
+        for line in lines.iter().filter(|line| !line.starts_with('#')) {
+            let name = line.split(['{', ' ']).next().unwrap();
+            let metric = ["_bucket", "_sum", "_count"]
+                .iter()
+                .find_map(|suffix| name.strip_suffix(suffix))
+                .filter(|metric| out.contains(&format!("# TYPE {} histogram", metric)))
+                .unwrap_or(name);
+            assert!(
+                out.contains(&format!("# HELP {} ", metric))
+                    && out.contains(&format!("# TYPE {} ", metric)),
+                "no help or type for {}",
+                line
+            );
+        }
+    }
+}
@@ -0,0 +1,578 @@
+//! Server settings gathered from the command line, environment variables and a config file
+//!
+//! Each setting is named after its command line flag. A flag takes precedence over the
+//! environment variable `MULTIPING_<FLAG>` (upper case, with `-` replaced by `_`), which
+//! takes precedence over the setting's key in the TOML config file given with `--config`.
+//! Listeners with settings of their own are only read from the file's `[[listeners]]`
+//! tables.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::str::FromStr;
+
+use clap::ArgMatches;
+use multiping::Error;
+
+/// The settings which can be given in the config file or environment, and their
+/// `section.key` in the config file
+const KEYS: &[(&str, &str)] = &[
+    ("address", "address"),
+    ("trusted-address", "trusted_address"),
+    ("tokens", "auth.tokens"),
+    ("stats-access", "auth.stats_access"),
+    ("admin", "auth.admins"),
+    ("admin-tokens", "auth.admin_tokens"),
+    ("bans", "auth.bans"),
+    ("conn-rate", "limits.conn_rate"),
+    ("ip-rate", "limits.ip_rate"),
+    ("rate-limit-action", "limits.rate_limit_action"),
+    ("max-connections", "limits.max_connections"),
+    ("max-per-ip", "limits.max_per_ip"),
+    ("allow", "limits.allow"),
+    ("deny", "limits.deny"),
+    ("max-message-size", "limits.max_message_size"),
+    ("max-offences", "limits.max_offences"),
+    ("ping-interval", "timeouts.ping_interval"),
+    ("idle-timeout", "timeouts.idle"),
+    ("session-timeout", "timeouts.session"),
+    ("timeout-warning", "timeouts.warning"),
+    ("history", "history.messages"),
+    ("history-age", "history.age"),
+    ("mailbox", "mailbox.messages"),
+    ("mailbox-ttl", "mailbox.ttl"),
+    ("max-unacked", "delivery.max_unacked"),
+    ("journal", "journal.dir"),
+    ("journal-sync", "journal.sync"),
+    ("journal-segment-size", "journal.segment_size"),
+    ("journal-max-size", "journal.max_size"),
+    ("journal-max-age", "journal.max_age"),
+    ("metrics", "metrics.address"),
+    ("log-format", "logging.format"),
+    ("log-filter", "logging.filter"),
+];
+
+/// The table array in the config file describing listeners with settings of their own
+const LISTENERS_KEY: &str = "listeners";
+
+/// A listener described by a `[[listeners]]` table in the config file
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileListener {
+    /// Where to listen, as given to `--address`
+    pub address: String,
+    /// Whether connections join without a token, as on a `--trusted-address`
+    pub trusted: bool,
+    /// The idle timeout in seconds, overriding `--idle-timeout`
+    pub idle_timeout: Option<u64>,
+    /// The session timeout in seconds, overriding `--session-timeout`
+    pub session_timeout: Option<u64>,
+}
+
+/// Where a setting's value came from
+enum Source {
+    Flag,
+    Env,
+    File,
+}
+
+/// The merged settings of the command line, environment and config file
+pub struct Settings<'a> {
+    matches: ArgMatches<'a>,
+    /// The path of the config file, if one was given
+    path: Option<String>,
+    /// The values in the config file, by flag name
+    file: HashMap<&'static str, Vec<String>>,
+    /// The listeners in the config file
+    listeners: Vec<FileListener>,
+    /// The environment variables, by name
+    env: HashMap<String, String>,
+}
+
+impl<'a> Settings<'a> {
+    /// Read the config file named by `--config`, if there is one, and the process's
+    /// environment
+    pub fn load(matches: ArgMatches<'a>) -> multiping::Result<Settings<'a>> {
+        let env = std::env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+            .collect();
+
+        Settings::from_sources(matches, env)
+    }
+
+    /// Read the config file named by `--config`, if there is one, taking environment
+    /// variables from `env` rather than the process
+    pub fn from_sources(
+        matches: ArgMatches<'a>,
+        env: HashMap<String, String>,
+    ) -> multiping::Result<Settings<'a>> {
+        let path = matches.value_of("config").map(str::to_string);
+        let (file, listeners) = match &path {
+            Some(path) => read_file(path)?,
+            None => (HashMap::new(), Vec::new()),
+        };
+
+        Ok(Settings {
+            matches,
+            path,
+            file,
+            listeners,
+            env,
+        })
+    }
+
+    /// Read the config file again, keeping the same flags and environment
+    pub fn reload(&self) -> multiping::Result<Settings<'a>> {
+        Settings::from_sources(self.matches.clone(), self.env.clone())
+    }
+
+    /// The value of a setting
+    pub fn value_of(&self, name: &str) -> Option<String> {
+        self.values_of(name).into_iter().next()
+    }
+
+    /// The values of a setting which can be given more than once, with environment
+    /// variables holding a comma separated list
+    pub fn values_of(&self, name: &str) -> Vec<String> {
+        match self.source(name) {
+            Some(Source::Flag) => self
+                .matches
+                .values_of(name)
+                .into_iter()
+                .flatten()
+                .map(str::to_string)
+                .collect(),
+            Some(Source::Env) => self.env[&env_var(name)]
+                .split(',')
+                .map(|value| value.trim().to_string())
+                .collect(),
+            Some(Source::File) => self.file[name].clone(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Parse a setting which must be a whole number
+    pub fn number<T: FromStr>(&self, name: &str) -> multiping::Result<Option<T>> {
+        self.value_of(name)
+            .map(|value| {
+                value.parse().map_err(|_| {
+                    Error::InvalidConfig(format!("{} must be a number", self.describe(name)))
+                })
+            })
+            .transpose()
+    }
+
+    /// Parse a setting with its type's `FromStr` implementation
+    pub fn parse<T>(&self, name: &str) -> multiping::Result<Option<T>>
+    where
+        T: FromStr<Err = Error>,
+    {
+        Ok(self.parse_all(name)?.into_iter().next())
+    }
+
+    /// Parse every value of a setting which can be given more than once
+    pub fn parse_all<T>(&self, name: &str) -> multiping::Result<Vec<T>>
+    where
+        T: FromStr<Err = Error>,
+    {
+        self.values_of(name)
+            .iter()
+            .map(|value| {
+                value.parse().map_err(|e| {
+                    let reason = match e {
+                        Error::InvalidConfig(reason) => reason,
+                        e => e.to_string(),
+                    };
+                    Error::InvalidConfig(format!("{}: {}", self.describe(name), reason))
+                })
+            })
+            .collect()
+    }
+
+    /// The listeners described in the config file
+    pub fn listeners(&self) -> &[FileListener] {
+        &self.listeners
+    }
+
+    /// Name where a listener in the config file was given, for error messages
+    pub fn describe_listener(&self, index: usize) -> String {
+        listener_name(self.path.as_deref().unwrap_or("the config file"), index)
+    }
+
+    /// Name where a setting was given, for error messages
+    pub fn describe(&self, name: &str) -> String {
+        match self.source(name) {
+            Some(Source::Env) => env_var(name),
+            Some(Source::File) => format!(
+                "`{}` in {}",
+                file_key(name).unwrap_or(name),
+                self.path.as_deref().unwrap_or("the config file")
+            ),
+            Some(Source::Flag) | None => format!("--{}", name),
+        }
+    }
+
+    /// Where the value of a setting comes from, if it was given at all
+    fn source(&self, name: &str) -> Option<Source> {
+        if self.matches.is_present(name) {
+            Some(Source::Flag)
+        } else if file_key(name).is_some() && self.env.contains_key(&env_var(name)) {
+            Some(Source::Env)
+        } else if self.file.contains_key(name) {
+            Some(Source::File)
+        } else {
+            None
+        }
+    }
+}
+
+/// The environment variable holding a setting
+fn env_var(name: &str) -> String {
+    format!("MULTIPING_{}", name.to_uppercase().replace('-', "_"))
+}
+
+/// The key of a setting in the config file
+fn file_key(name: &str) -> Option<&'static str> {
+    KEYS.iter()
+        .find(|(flag, _)| *flag == name)
+        .map(|(_, key)| *key)
+}
+
+/// The settings in a config file, by flag name
+type FileSettings = HashMap<&'static str, Vec<String>>;
+
+/// Read the settings and listeners in a config file
+fn read_file(path: &str) -> multiping::Result<(FileSettings, Vec<FileListener>)> {
+    debug!("read config file {}", path);
+
+    let contents = fs::read_to_string(path)
+        .map_err(|e| Error::InvalidConfig(format!("failed to read {}: {}", path, e)))?;
+    let mut table: toml::value::Table = toml::from_str(&contents)
+        .map_err(|e| Error::InvalidConfig(format!("failed to parse {}: {}", path, e)))?;
+
+    let listeners = match table.remove(LISTENERS_KEY) {
+        Some(toml::Value::Array(tables)) => tables
+            .iter()
+            .enumerate()
+            .map(|(index, table)| {
+                read_listener(table).map_err(|reason| {
+                    Error::InvalidConfig(format!("{}: {}", listener_name(path, index), reason))
+                })
+            })
+            .collect::<multiping::Result<_>>()?,
+        Some(_) => {
+            return Err(Error::InvalidConfig(format!(
+                "`{}` in {} must be an array of tables",
+                LISTENERS_KEY, path
+            )))
+        }
+        None => Vec::new(),
+    };
+
+    let mut settings = HashMap::new();
+    let mut unknown = Vec::new();
+
+    for (key, value) in flatten(&table) {
+        match KEYS.iter().find(|(_, k)| *k == key) {
+            Some((flag, _)) => {
+                let values = to_strings(value).ok_or_else(|| {
+                    Error::InvalidConfig(format!(
+                        "`{}` in {} must be a string, number or boolean, or a list of them",
+                        key, path
+                    ))
+                })?;
+                settings.insert(*flag, values);
+            }
+            None => unknown.push(key),
+        }
+    }
+
+    if !unknown.is_empty() {
+        return Err(Error::InvalidConfig(format!(
+            "unknown settings in {}: {}",
+            path,
+            unknown.join(", ")
+        )));
+    }
+
+    Ok((settings, listeners))
+}
+
+/// Read a `[[listeners]]` table, failing with the reason it is invalid
+fn read_listener(value: &toml::Value) -> Result<FileListener, String> {
+    let table = value.as_table().ok_or("must be a table")?;
+
+    let mut listener = FileListener {
+        address: String::new(),
+        trusted: false,
+        idle_timeout: None,
+        session_timeout: None,
+    };
+    let mut address = None;
+
+    for (key, value) in table {
+        match key.as_str() {
+            "address" => address = Some(value.as_str().ok_or("`address` must be a string")?),
+            "trusted" => {
+                listener.trusted = value.as_bool().ok_or("`trusted` must be a boolean")?;
+            }
+            "idle_timeout" => listener.idle_timeout = Some(seconds(key, value)?),
+            "session_timeout" => listener.session_timeout = Some(seconds(key, value)?),
+            key => return Err(format!("unknown setting `{}`", key)),
+        }
+    }
+
+    listener.address = address.ok_or("`address` is missing")?.to_string();
+    Ok(listener)
+}
+
+/// A number of seconds given for `key` in a `[[listeners]]` table
+fn seconds(key: &str, value: &toml::Value) -> Result<u64, String> {
+    value
+        .as_integer()
+        .and_then(|secs| u64::try_from(secs).ok())
+        .ok_or_else(|| format!("`{}` must be a number of seconds", key))
+}
+
+/// Name a `[[listeners]]` table in a config file, for error messages
+fn listener_name(path: &str, index: usize) -> String {
+    format!("`{}[{}]` in {}", LISTENERS_KEY, index, path)
+}
+
+/// The values in a table by their dotted keys, descending into sections
+fn flatten(table: &toml::value::Table) -> Vec<(String, &toml::Value)> {
+    let mut values = Vec::new();
+
+    for (key, value) in table {
+        match value {
+            toml::Value::Table(section) => {
+                for (inner, value) in flatten(section) {
+                    values.push((format!("{}.{}", key, inner), value));
+                }
+            }
+            value => values.push((key.clone(), value)),
+        }
+    }
+
+    values
+}
+
+/// A config file value as it would be written on the command line
+fn to_strings(value: &toml::Value) -> Option<Vec<String>> {
+    match value {
+        toml::Value::Array(values) => values.iter().map(scalar).collect(),
+        value => scalar(value).map(|value| vec![value]),
+    }
+}
+
+/// A single string, number or boolean from the config file
+fn scalar(value: &toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(s) => Some(s.clone()),
+        toml::Value::Integer(n) => Some(n.to_string()),
+        toml::Value::Boolean(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use clap::{App, Arg};
+
+    /// Parse `args` as the command line, with `config` as the contents of the config file
+    fn settings(args: &[&str], config: &str) -> multiping::Result<Settings<'static>> {
+        settings_with_env(args, config, &[])
+    }
+
+    /// Parse `args` as the command line, with `config` as the contents of the config file
+    /// and `env` as the environment variables
+    fn settings_with_env(
+        args: &[&str],
+        config: &str,
+        env: &[(&str, &str)],
+    ) -> multiping::Result<Settings<'static>> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.toml");
+        fs::write(&path, config).unwrap();
+
+        let app = App::new("server")
+            .arg(Arg::with_name("config").long("config").takes_value(true))
+            .arg(Arg::with_name("history").long("history").takes_value(true))
+            .arg(Arg::with_name("mailbox").long("mailbox").takes_value(true))
+            .arg(
+                Arg::with_name("max-unacked")
+                    .long("max-unacked")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("allow")
+                    .long("allow")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1),
+            )
+            .arg(
+                Arg::with_name("deny")
+                    .long("deny")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1),
+            );
+        let path = path.to_str().unwrap().to_string();
+        let mut argv = vec!["server", "--config", &path];
+        argv.extend_from_slice(args);
+
+        let env = env
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+
+        Settings::from_sources(app.get_matches_from(argv), env)
+    }
+
+    #[test]
+    fn flag_overrides_environment_and_file() {
+        let settings = settings_with_env(
+            &["--history", "10"],
+            "[history]\nmessages = 30\n",
+            &[("MULTIPING_HISTORY", "20")],
+        )
+        .unwrap();
+
+        assert_eq!(settings.number::<usize>("history").unwrap(), Some(10));
+        assert_eq!(settings.describe("history"), "--history");
+    }
+
+    #[test]
+    fn environment_overrides_file() {
+        let settings = settings_with_env(
+            &[],
+            "[mailbox]\nmessages = 7\n",
+            &[("MULTIPING_MAILBOX", "5")],
+        )
+        .unwrap();
+
+        assert_eq!(settings.value_of("mailbox"), Some("5".to_string()));
+        assert_eq!(settings.describe("mailbox"), "MULTIPING_MAILBOX");
+    }
+
+    #[test]
+    fn file_applies_without_flag_or_environment() {
+        let settings = settings(&[], "[delivery]\nmax_unacked = 9\n").unwrap();
+
+        assert_eq!(settings.number::<usize>("max-unacked").unwrap(), Some(9));
+        assert!(settings
+            .describe("max-unacked")
+            .starts_with("`delivery.max_unacked` in "));
+        assert_eq!(settings.value_of("history"), None);
+    }
+
+    #[test]
+    fn lists_come_from_repeated_flags_comma_separated_variables_or_arrays() {
+        let settings = settings(
+            &["--allow", "10.0.0.0/8", "--allow", "::1"],
+            "[limits]\nallow = [\"127.0.0.1\"]\ndeny = [\"192.0.2.0/24\", \"198.51.100.1\"]\n",
+        )
+        .unwrap();
+
+        assert_eq!(settings.values_of("allow"), vec!["10.0.0.0/8", "::1"]);
+        assert_eq!(
+            settings.values_of("deny"),
+            vec!["192.0.2.0/24", "198.51.100.1"]
+        );
+
+        let settings = settings_with_env(
+            &[],
+            "",
+            &[("MULTIPING_DENY", "192.0.2.0/24, 203.0.113.0/24")],
+        )
+        .unwrap();
+        assert_eq!(
+            settings.values_of("deny"),
+            vec!["192.0.2.0/24", "203.0.113.0/24"]
+        );
+    }
+
+    #[test]
+    fn invalid_values_name_their_source() {
+        let settings = settings(&["--history", "lots"], "").unwrap();
+        match settings.number::<usize>("history") {
+            Err(Error::InvalidConfig(e)) => assert_eq!(e, "--history must be a number"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        match settings(&[], "[history]\nmesages = 10\n[other]\nkey = 1\n") {
+            Err(Error::InvalidConfig(e)) => {
+                assert!(e.contains("history.mesages"), "{}", e);
+                assert!(e.contains("other.key"), "{}", e);
+            }
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn listeners_are_read_from_their_own_tables() {
+        let settings = settings(
+            &[],
+            "[timeouts]\nidle = 60\n\n\
+             [[listeners]]\naddress = \"127.0.0.1:3000\"\n\n\
+             [[listeners]]\naddress = \"unix:/tmp/multiping.sock\"\ntrusted = true\n\
+             idle_timeout = 3600\nsession_timeout = 86400\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            settings.listeners(),
+            &[
+                FileListener {
+                    address: "127.0.0.1:3000".to_string(),
+                    trusted: false,
+                    idle_timeout: None,
+                    session_timeout: None,
+                },
+                FileListener {
+                    address: "unix:/tmp/multiping.sock".to_string(),
+                    trusted: true,
+                    idle_timeout: Some(3600),
+                    session_timeout: Some(86400),
+                },
+            ]
+        );
+        assert_eq!(settings.value_of("idle-timeout"), Some("60".to_string()));
+    }
+
+    #[test]
+    fn invalid_listeners_are_rejected() {
+        for (config, reason) in [
+            ("[[listeners]]\ntrusted = true\n", "`address` is missing"),
+            (
+                "[[listeners]]\naddress = \"127.0.0.1:0\"\nidle_timeout = -1\n",
+                "`idle_timeout` must be a number of seconds",
+            ),
+            (
+                "[[listeners]]\naddress = \"127.0.0.1:0\"\nidle = 1\n",
+                "unknown setting `idle`",
+            ),
+        ] {
+            match settings(&[], config) {
+                Err(Error::InvalidConfig(e)) => {
+                    assert!(e.starts_with("`listeners[0]` in "), "{}", e);
+                    assert!(e.ends_with(reason), "{}", e);
+                }
+                other => panic!("unexpected {:?}", other.map(|_| ())),
+            }
+        }
+        assert!(settings(&[], "listeners = \"127.0.0.1:0\"\n").is_err());
+    }
+
+    #[test]
+    fn values_must_be_scalars_or_lists_of_them() {
+        assert!(settings(&[], "[limits]\nallow = [[\"127.0.0.1\"]]\n").is_err());
+        assert!(settings(&[], "[history]\nmessages = 1.5\n").is_err());
+        assert!(settings(&[], "[history\n").is_err());
+    }
+}